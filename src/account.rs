@@ -0,0 +1,874 @@
+use std::collections::HashMap;
+
+use actix_session::Session;
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, ReqData};
+use actix_web::{get, post, HttpRequest, HttpResponseBuilder, Responder};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{AuditEvent, RequestOrigin};
+use crate::auth::guard::{Invite, RequirePermission, RequireUser};
+use crate::auth::middleware::{Login, UserInfo};
+use crate::auth::{oidc, totp};
+use crate::csrf::CsrfToken;
+use crate::db::{format_timestamp, unix_now, InviteError};
+use crate::short::LinkStats;
+
+#[derive(Template)]
+#[template(path = "account.html")]
+struct AccountTemplate {
+    name: String,
+    is_admin: bool,
+    can_invite: bool,
+    passkeys: bool,
+    oidc: Option<String>,
+}
+
+#[get("/account")]
+async fn account(data: Data<crate::AppData>, info: RequireUser) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            AccountTemplate {
+                name: info.name.clone(),
+                is_admin: info.perms.is_admin(),
+                can_invite: info.perms.can_invite(),
+                passkeys: data.webauthn.is_some(),
+                oidc: data.oidc.as_ref().map(|oidc| oidc.name().to_owned()),
+            }
+            .to_string(),
+        )
+}
+
+/// Re-checks the password of an already logged in user, for sensitive actions.
+async fn check_password(data: &crate::AppData, info: &UserInfo, password: &str) -> bool {
+    crate::auth::verify_password(password)
+        && data.db.verify_user(&info.name, password).await == Some(info.id)
+}
+
+/// Re-checks that an already logged in user is who they say they are, for sensitive actions,
+/// either by their password or by a fresh login with the OIDC provider.
+async fn confirm_identity(
+    data: &crate::AppData,
+    info: &UserInfo,
+    session: &Session,
+    password: &str,
+) -> bool {
+    (data.oidc.is_some() && oidc::reauthenticated(session, info.id))
+        || check_password(data, info, password).await
+}
+
+/// How the pages for sensitive actions let the user confirm it's them besides their password.
+struct Confirmation {
+    /// The OIDC provider, if the user has an identity there linked to their account.
+    provider: Option<String>,
+    /// Whether they've just logged in with it, so that no password is needed.
+    confirmed: bool,
+}
+
+impl Confirmation {
+    async fn load(data: &crate::AppData, info: &UserInfo, session: &Session) -> Self {
+        let provider = match &data.oidc {
+            Some(oidc) if data.db.has_oidc_identity(info.id).await => Some(oidc.name().to_owned()),
+            _ => None,
+        };
+
+        Self {
+            confirmed: provider.is_some() && oidc::reauthenticated(session, info.id),
+            provider,
+        }
+    }
+}
+
+#[derive(Template)]
+#[template(path = "account_password.html")]
+struct PasswordTemplate {
+    error: Option<&'static str>,
+    confirmation: Confirmation,
+    csrf_token: String,
+}
+
+#[get("/account/password")]
+async fn password_get(
+    data: Data<crate::AppData>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            PasswordTemplate {
+                error: None,
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct PasswordForm {
+    /// Not needed after a fresh login with the OIDC provider.
+    #[serde(default)]
+    current_password: String,
+    new_password: String,
+    confirm_password: String,
+}
+
+#[post("/account/password")]
+async fn password_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<PasswordForm>,
+    session: Session,
+    login: ReqData<Login>,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let strength = crate::auth::check_new_password(&data, &info.name, &form.new_password);
+    let error = if !confirm_identity(&data, &info, &session, &form.current_password).await {
+        "Incorrect current password."
+    } else if let Err(weak) = strength {
+        weak.message()
+    } else if form.new_password != form.confirm_password {
+        "The new passwords don't match."
+    } else if data.db.change_password(info.id, &form.new_password).await {
+        data.user_cache.invalidate(info.id);
+        data.db
+            .audit(
+                AuditEvent::PasswordChanged,
+                Some(info.id),
+                &info.name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+        session
+            .insert(crate::session_keys::SUCCESSFUL, "changed your password")
+            .unwrap();
+        // every other session was just invalidated, so this one has to be renewed
+        login.login(info.id);
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/"))
+            .finish();
+    } else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            PasswordTemplate {
+                error: Some(error),
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize)]
+struct AccountExport {
+    id: i64,
+    name: String,
+    roles: Vec<String>,
+    permissions: PermissionsExport,
+    show_clicks_on_profile: bool,
+    short_links: Vec<LinkExport>,
+}
+
+#[derive(Serialize)]
+struct PermissionsExport {
+    admin: bool,
+    short: bool,
+    invite: bool,
+}
+
+#[derive(Serialize)]
+struct LinkExport {
+    short: String,
+    url: String,
+    title: Option<String>,
+    public: bool,
+    created_at: i64,
+    clicks: i64,
+    unique_visitors: i64,
+}
+
+#[get("/account/export.json")]
+async fn export(data: Data<crate::AppData>, info: RequireUser) -> impl Responder {
+    let mut stats: HashMap<i64, LinkStats> = data
+        .db
+        .get_link_stats(info.id)
+        .await
+        .into_iter()
+        .map(|stat| (stat.link_id, stat))
+        .collect();
+
+    let short_links = data
+        .db
+        .get_links(info.id)
+        .await
+        .into_iter()
+        .map(|link| {
+            let (clicks, unique_visitors) = stats
+                .remove(&link.id)
+                .map(|stat| (stat.clicks, stat.unique_visitors))
+                .unwrap_or_default();
+
+            LinkExport {
+                short: link.short,
+                url: link.url,
+                title: link.title,
+                public: link.public,
+                created_at: link.created_at,
+                clicks,
+                unique_visitors,
+            }
+        })
+        .collect();
+
+    let perms = data.db.get_permissions(info.id).await;
+    let export = AccountExport {
+        id: info.id,
+        name: info.name.clone(),
+        roles: data.db.get_role_names(info.id).await,
+        permissions: PermissionsExport {
+            admin: perms.is_admin(),
+            short: perms.is_short(),
+            invite: perms.can_invite(),
+        },
+        show_clicks_on_profile: data.db.get_public_clicks(info.id).await,
+        short_links,
+    };
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::json())
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!("{}.json", info.name))],
+        })
+        .body(serde_json::to_string_pretty(&export).unwrap())
+}
+
+#[derive(Template)]
+#[template(path = "account_delete.html")]
+struct DeleteTemplate {
+    failed: bool,
+    confirmation: Confirmation,
+    csrf_token: String,
+}
+
+#[get("/account/delete")]
+async fn delete_get(
+    data: Data<crate::AppData>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            DeleteTemplate {
+                failed: false,
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct DeleteForm {
+    /// Not needed after a fresh login with the OIDC provider.
+    #[serde(default)]
+    password: String,
+}
+
+#[post("/account/delete")]
+async fn delete_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<DeleteForm>,
+    session: Session,
+    login: ReqData<Login>,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    if confirm_identity(&data, &info, &session, &form.password).await
+        && data.db.delete_user(info.id, &RequestOrigin::of(&req)).await
+    {
+        data.user_cache.invalidate(info.id);
+        login.logout();
+        session
+            .insert(crate::session_keys::SUCCESSFUL, "deleted your account")
+            .unwrap();
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            DeleteTemplate {
+                failed: true,
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Template)]
+#[template(path = "account_oidc.html")]
+struct LinkTemplate {
+    provider: String,
+    failed: bool,
+    confirmation: Confirmation,
+    csrf_token: String,
+}
+
+#[get("/account/oidc")]
+async fn oidc_get(
+    data: Data<crate::AppData>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let Some(oidc) = &data.oidc else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            LinkTemplate {
+                provider: oidc.name().to_owned(),
+                failed: false,
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct LinkForm {
+    /// Not needed after a fresh login with the OIDC provider.
+    #[serde(default)]
+    password: String,
+}
+
+/// Linking an identity lets it stand in for the password, so it needs the password too.
+#[post("/account/oidc")]
+async fn oidc_link(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<LinkForm>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let Some(oidc) = &data.oidc else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    if confirm_identity(&data, &info, &session, &form.password).await {
+        return oidc::link(&req, oidc, &session, info.id).await;
+    }
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            LinkTemplate {
+                provider: oidc.name().to_owned(),
+                failed: true,
+                confirmation: Confirmation::load(&data, &info, &session).await,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+struct TotpEnrollment {
+    secret: String,
+    qr_code: String,
+}
+
+#[derive(Template)]
+#[template(path = "account_2fa.html")]
+struct TotpTemplate {
+    enrollment: Option<TotpEnrollment>,
+    recovery_codes: Option<Vec<String>>,
+    recovery_codes_left: i64,
+    error: Option<&'static str>,
+    confirmation: Confirmation,
+    csrf_token: String,
+}
+
+impl TotpTemplate {
+    async fn load(
+        data: &crate::AppData,
+        info: &UserInfo,
+        session: &Session,
+        csrf: &CsrfToken,
+    ) -> Self {
+        let enrollment = if data.db.totp_enabled(info.id).await {
+            session.remove(crate::session_keys::TOTP_SECRET);
+            None
+        } else {
+            // the secret only makes it to the DB once the user has proven they enrolled it
+            let secret = match session
+                .get::<String>(crate::session_keys::TOTP_SECRET)
+                .unwrap()
+            {
+                Some(secret) => secret,
+                None => {
+                    let secret = totp::generate_secret();
+                    session
+                        .insert(crate::session_keys::TOTP_SECRET, &secret)
+                        .unwrap();
+                    secret
+                }
+            };
+
+            Some(TotpEnrollment {
+                qr_code: totp::qr_code(&secret, &info.name).unwrap(),
+                secret,
+            })
+        };
+
+        TotpTemplate {
+            enrollment,
+            recovery_codes: None,
+            recovery_codes_left: data.db.count_recovery_codes(info.id).await,
+            error: None,
+            confirmation: Confirmation::load(data, info, session).await,
+            csrf_token: csrf.get().to_owned(),
+        }
+    }
+}
+
+#[get("/account/2fa")]
+async fn totp_get(
+    data: Data<crate::AppData>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            TotpTemplate::load(&data, &info, &session, &csrf)
+                .await
+                .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct TotpEnableForm {
+    code: String,
+}
+
+#[post("/account/2fa/enable")]
+async fn totp_enable(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<TotpEnableForm>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let secret = session
+        .get::<String>(crate::session_keys::TOTP_SECRET)
+        .unwrap();
+    let step = secret
+        .as_deref()
+        .and_then(|secret| totp::check(secret, form.code.trim(), unix_now()));
+
+    if let (Some(secret), Some(step)) = (secret, step) {
+        let recovery_codes = data.db.enable_totp(info.id, &secret, step).await;
+        data.db
+            .audit(
+                AuditEvent::TotpEnabled,
+                Some(info.id),
+                &info.name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(ContentType::html())
+            .body(
+                TotpTemplate {
+                    recovery_codes: Some(recovery_codes),
+                    ..TotpTemplate::load(&data, &info, &session, &csrf).await
+                }
+                .to_string(),
+            );
+    }
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            TotpTemplate {
+                error: Some(
+                    "Incorrect code. Please make sure that your device's clock is correct.",
+                ),
+                ..TotpTemplate::load(&data, &info, &session, &csrf).await
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct TotpPasswordForm {
+    /// Not needed after a fresh login with the OIDC provider.
+    #[serde(default)]
+    password: String,
+}
+
+#[post("/account/2fa/disable")]
+async fn totp_disable(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<TotpPasswordForm>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    if confirm_identity(&data, &info, &session, &form.password).await {
+        data.db.disable_totp(info.id).await;
+        data.db
+            .audit(
+                AuditEvent::TotpDisabled,
+                Some(info.id),
+                &info.name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/account/2fa"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            TotpTemplate {
+                error: Some("Incorrect password."),
+                ..TotpTemplate::load(&data, &info, &session, &csrf).await
+            }
+            .to_string(),
+        )
+}
+
+#[post("/account/2fa/recovery")]
+async fn totp_recovery(
+    data: Data<crate::AppData>,
+    form: web::Form<TotpPasswordForm>,
+    session: Session,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    if !data.db.totp_enabled(info.id).await {
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/account/2fa"))
+            .finish();
+    }
+
+    if confirm_identity(&data, &info, &session, &form.password).await {
+        let recovery_codes = data.db.regenerate_recovery_codes(info.id).await;
+
+        return HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(ContentType::html())
+            .body(
+                TotpTemplate {
+                    recovery_codes: Some(recovery_codes),
+                    ..TotpTemplate::load(&data, &info, &session, &csrf).await
+                }
+                .to_string(),
+            );
+    }
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            TotpTemplate {
+                error: Some("Incorrect password."),
+                ..TotpTemplate::load(&data, &info, &session, &csrf).await
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Debug, Clone)]
+pub struct ActiveSession {
+    pub id: i64,
+    pub created_at: i64,
+    pub last_seen: i64,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+struct SessionView {
+    id: i64,
+    current: bool,
+    created_at: String,
+    last_seen: String,
+    ip: String,
+    user_agent: String,
+}
+
+#[derive(Template)]
+#[template(path = "account_sessions.html")]
+struct SessionsTemplate {
+    sessions: Vec<SessionView>,
+    csrf_token: String,
+}
+
+#[get("/account/sessions")]
+async fn sessions_get(
+    data: Data<crate::AppData>,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let sessions = data
+        .db
+        .get_sessions(info.id)
+        .await
+        .into_iter()
+        .map(|session| SessionView {
+            id: session.id,
+            current: session.id == info.session_id,
+            created_at: format_timestamp(session.created_at),
+            last_seen: format_timestamp(session.last_seen),
+            ip: session.ip.unwrap_or_else(|| "-".into()),
+            user_agent: session.user_agent.unwrap_or_else(|| "-".into()),
+        })
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            SessionsTemplate {
+                sessions,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Deserialize)]
+struct RevokeSessionForm {
+    id: i64,
+}
+
+#[post("/account/sessions/revoke")]
+async fn revoke_session(
+    data: Data<crate::AppData>,
+    form: web::Form<RevokeSessionForm>,
+    session: Session,
+    login: ReqData<Login>,
+    info: RequireUser,
+) -> impl Responder {
+    // revoking the current session is the same as logging out
+    if form.id == info.session_id {
+        login.logout();
+        session
+            .insert(crate::session_keys::SUCCESSFUL, "logged out")
+            .unwrap();
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/"))
+            .finish();
+    }
+
+    if data.db.revoke_session(info.id, form.id).await {
+        data.user_cache.invalidate(info.id);
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/account/sessions"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+#[post("/account/sessions/revoke_others")]
+async fn revoke_other_sessions(data: Data<crate::AppData>, info: RequireUser) -> impl Responder {
+    data.db
+        .revoke_other_sessions(info.id, info.session_id)
+        .await;
+    data.user_cache.invalidate(info.id);
+
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", "/account/sessions"))
+        .finish()
+}
+
+/// How long invite tickets stay valid, in days.
+const INVITE_LIFETIME: i64 = 7;
+
+struct InviteView {
+    id: i64,
+    name: String,
+    url: String,
+    expires_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "account_invites.html")]
+struct InvitesTemplate {
+    newticket: Option<String>,
+    error: Option<&'static str>,
+    invites_left: i64,
+    invites: Vec<InviteView>,
+    invitees: Vec<String>,
+    lifetime: i64,
+    csrf_token: String,
+}
+
+async fn render_invites(
+    req: &HttpRequest,
+    data: &crate::AppData,
+    info: &UserInfo,
+    csrf: &CsrfToken,
+    newticket: Option<String>,
+    error: Option<&'static str>,
+) -> String {
+    let invites = data
+        .db
+        .get_issued_tickets(info.id)
+        .await
+        .into_iter()
+        .map(|ticket| InviteView {
+            id: ticket.id,
+            url: crate::admin::registration_url(req, &ticket.ticket),
+            name: ticket.name,
+            expires_at: ticket
+                .expires_at
+                .map(format_timestamp)
+                .unwrap_or_else(|| "never".into()),
+        })
+        .collect();
+
+    InvitesTemplate {
+        newticket,
+        error,
+        invites_left: data.db.get_invites_left(info.id).await,
+        invites,
+        invitees: data.db.get_invitees(info.id).await,
+        lifetime: INVITE_LIFETIME,
+        csrf_token: csrf.get().to_owned(),
+    }
+    .to_string()
+}
+
+#[get("/account/invites")]
+async fn invites_get(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: RequirePermission<Invite>,
+    csrf: CsrfToken,
+    session: Session,
+) -> impl Responder {
+    let newticket = session
+        .remove_as::<String>(crate::session_keys::NEW_TICKET)
+        .map(Result::unwrap);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(render_invites(&req, &data, &info, &csrf, newticket, None).await)
+}
+
+#[derive(Serialize, Deserialize)]
+struct InviteForm {
+    name: String,
+}
+
+#[post("/account/invites")]
+async fn invites_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: RequirePermission<Invite>,
+    csrf: CsrfToken,
+    form: web::Form<InviteForm>,
+    session: Session,
+) -> impl Responder {
+    let error = if !crate::auth::verify_username(&form.name) {
+        "Invalid username. Usernames must be between 1-64 characters, \
+        and consist only of a-z, A-Z, 0-9 or _."
+    } else {
+        let expires_at = unix_now() + INVITE_LIFETIME * 24 * 60 * 60;
+        match data
+            .db
+            .generate_invite_ticket(&form.name, info.id, expires_at)
+            .await
+        {
+            Ok(ticket) => {
+                data.db
+                    .audit(
+                        AuditEvent::TicketIssued,
+                        Some(info.id),
+                        &form.name,
+                        &RequestOrigin::of(&req),
+                    )
+                    .await;
+                session
+                    .insert(
+                        crate::session_keys::NEW_TICKET,
+                        crate::admin::registration_url(&req, &ticket),
+                    )
+                    .unwrap();
+
+                return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+                    .insert_header(("Location", "/account/invites"))
+                    .finish();
+            }
+            Err(InviteError::NoInvitesLeft) => "You have no invites left.",
+            Err(InviteError::NameTaken) => {
+                "That name is already taken by a user or another ticket."
+            }
+        }
+    };
+
+    HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+        .content_type(ContentType::html())
+        .body(render_invites(&req, &data, &info, &csrf, None, Some(error)).await)
+}
+
+#[derive(Deserialize)]
+struct RevokeInviteForm {
+    id: i64,
+}
+
+#[post("/account/invites/revoke")]
+async fn revoke_invite(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: RequirePermission<Invite>,
+    form: web::Form<RevokeInviteForm>,
+) -> impl Responder {
+    if let Some(name) = data.db.revoke_issued_ticket(info.id, form.id).await {
+        data.db
+            .audit(
+                AuditEvent::TicketRevoked,
+                Some(info.id),
+                &name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/account/invites"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
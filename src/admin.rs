@@ -0,0 +1,1138 @@
+use std::collections::{HashMap, HashSet};
+
+use actix_session::Session;
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{get, post, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
+use askama::Template;
+use serde::{Deserialize, Serialize};
+
+use crate::audit::{AuditEvent, AuditFilter, RequestOrigin};
+use crate::auth::guard::{Admin, Hidden, RequirePermission};
+use crate::auth::middleware::UserInfo;
+use crate::auth::throttle::ThrottleKey;
+use crate::csrf::CsrfToken;
+use crate::db::{
+    format_timestamp, unix_now, Capability, InvitedUser, Role, UserPermissions, ADMIN_ROLE,
+    SHORT_ROLE,
+};
+
+#[derive(Debug, Clone)]
+pub struct RegistrationTicket {
+    pub id: i64,
+    pub name: String,
+    pub ticket: String,
+    pub issued_by: Option<String>,
+    pub issued_at: i64,
+    pub expires_at: Option<i64>,
+    pub grant_admin: bool,
+    pub grant_short: bool,
+}
+
+struct TicketView {
+    id: i64,
+    name: String,
+    url: String,
+    issued_by: String,
+    issued_at: String,
+    expires_at: String,
+    grants: String,
+}
+
+/// How long a ticket may stay valid, in days.
+const TICKET_LIFETIMES: [i64; 4] = [1, 7, 30, 90];
+const DEFAULT_TICKET_LIFETIME: i64 = 7;
+
+struct TicketLifetime {
+    days: i64,
+    default: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_tickets.html")]
+struct TicketsTemplate {
+    newticket: Option<String>,
+    error: Option<String>,
+    tickets: Vec<TicketView>,
+    lifetimes: Vec<TicketLifetime>,
+    csrf_token: String,
+}
+
+/// The full URL a ticket holder should visit to register, prefilled with their ticket.
+pub fn registration_url(req: &HttpRequest, ticket: &str) -> String {
+    ticket_url(req, "/register", ticket)
+}
+
+/// The full URL a reset ticket holder should visit to set a new password.
+fn reset_url(req: &HttpRequest, ticket: &str) -> String {
+    ticket_url(req, "/reset", ticket)
+}
+
+fn ticket_url(req: &HttpRequest, path: &str, ticket: &str) -> String {
+    let conn = req.connection_info();
+    format!(
+        "{}://{}{path}?{}",
+        conn.scheme(),
+        conn.host(),
+        serde_urlencoded::to_string([("ticket", ticket)]).unwrap()
+    )
+}
+
+async fn render_tickets(
+    req: &HttpRequest,
+    data: &crate::AppData,
+    csrf: &CsrfToken,
+    newticket: Option<String>,
+    error: Option<String>,
+) -> String {
+    let tickets = data
+        .db
+        .get_registration_tickets()
+        .await
+        .into_iter()
+        .map(|ticket| {
+            let grants = [(ticket.grant_admin, "admin"), (ticket.grant_short, "short")]
+                .into_iter()
+                .filter_map(|(granted, name)| granted.then_some(name))
+                .collect::<Vec<_>>()
+                .join(", ");
+
+            TicketView {
+                id: ticket.id,
+                url: registration_url(req, &ticket.ticket),
+                name: ticket.name,
+                issued_by: ticket.issued_by.unwrap_or_else(|| "-".into()),
+                issued_at: format_timestamp(ticket.issued_at),
+                expires_at: ticket
+                    .expires_at
+                    .map(format_timestamp)
+                    .unwrap_or_else(|| "never".into()),
+                grants,
+            }
+        })
+        .collect();
+
+    TicketsTemplate {
+        newticket,
+        error,
+        tickets,
+        lifetimes: TICKET_LIFETIMES
+            .into_iter()
+            .map(|days| TicketLifetime {
+                days,
+                default: days == DEFAULT_TICKET_LIFETIME,
+            })
+            .collect(),
+        csrf_token: csrf.get().to_owned(),
+    }
+    .to_string()
+}
+
+#[get("/admin/tickets")]
+async fn tickets_get(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> impl Responder {
+    let newticket = session
+        .remove_as::<String>(crate::session_keys::NEW_TICKET)
+        .map(Result::unwrap);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(render_tickets(&req, &data, &csrf, newticket, None).await)
+}
+
+#[derive(Serialize, Deserialize)]
+struct TicketForm {
+    name: String,
+    days: i64,
+    admin: Option<String>,
+    short: Option<String>,
+}
+
+#[post("/admin/tickets")]
+async fn tickets_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<TicketForm>,
+    session: Session,
+) -> impl Responder {
+    if crate::auth::verify_username(&form.name) && TICKET_LIFETIMES.contains(&form.days) {
+        let mut perms = UserPermissions::default();
+        perms.set(Capability::Admin, form.admin.is_some());
+        perms.set(Capability::Short, form.short.is_some());
+
+        let expires_at = unix_now() + form.days * 24 * 60 * 60;
+        if let Some(ticket) = data
+            .db
+            .generate_registration_ticket(&form.name, Some(info.id), expires_at, perms)
+            .await
+        {
+            data.db
+                .audit(
+                    AuditEvent::TicketIssued,
+                    Some(info.id),
+                    &form.name,
+                    &RequestOrigin::of(&req),
+                )
+                .await;
+            session
+                .insert(
+                    crate::session_keys::NEW_TICKET,
+                    registration_url(&req, &ticket),
+                )
+                .unwrap();
+
+            return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+                .insert_header(("Location", "/admin/tickets"))
+                .finish();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+        .content_type(ContentType::html())
+        .body(
+            render_tickets(
+                &req,
+                &data,
+                &csrf,
+                None,
+                Some(
+                    "Couldn't issue a ticket. Please make sure that the name is valid, \
+                    and that it isn't already taken by a user or another ticket."
+                        .into(),
+                ),
+            )
+            .await,
+        )
+}
+
+#[derive(Deserialize)]
+struct RevokeTicketForm {
+    id: i64,
+}
+
+#[post("/admin/tickets/revoke")]
+async fn revoke_ticket(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    form: web::Form<RevokeTicketForm>,
+) -> impl Responder {
+    if let Some(name) = data.db.revoke_registration_ticket(form.id).await {
+        data.db
+            .audit(
+                AuditEvent::TicketRevoked,
+                Some(info.id),
+                &name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/admin/tickets"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+struct LockoutView {
+    key: String,
+    failures: u32,
+    locked_until: String,
+}
+
+#[derive(Template)]
+#[template(path = "admin_lockouts.html")]
+struct LockoutsTemplate {
+    lockouts: Vec<LockoutView>,
+    csrf_token: String,
+}
+
+#[get("/admin/lockouts")]
+async fn lockouts_get(
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let now = unix_now();
+    let lockouts = data
+        .state
+        .login_throttle
+        .lockouts()
+        .into_iter()
+        .map(|lockout| LockoutView {
+            key: lockout.key.to_string(),
+            failures: lockout.failures,
+            locked_until: if lockout.locked_until > now {
+                format_timestamp(lockout.locked_until)
+            } else {
+                "-".into()
+            },
+        })
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            LockoutsTemplate {
+                lockouts,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Deserialize)]
+struct ClearLockoutForm {
+    key: String,
+}
+
+#[post("/admin/lockouts/clear")]
+async fn clear_lockout(
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    form: web::Form<ClearLockoutForm>,
+) -> impl Responder {
+    if let Ok(key) = form.key.parse::<ThrottleKey>() {
+        if data.state.login_throttle.clear(&key) {
+            return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+                .insert_header(("Location", "/admin/lockouts"))
+                .finish();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+const AUDIT_PAGE_SIZE: i64 = 100;
+
+struct AuditView {
+    at: String,
+    event: String,
+    actor: String,
+    subject: String,
+    ip: String,
+    user_agent: String,
+}
+
+struct EventOption {
+    name: &'static str,
+    selected: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_audit.html")]
+struct AuditTemplate {
+    records: Vec<AuditView>,
+    events: Vec<EventOption>,
+    query: AuditQuery,
+    older: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AuditQuery {
+    #[serde(default)]
+    event: String,
+    #[serde(default)]
+    user: String,
+    #[serde(default)]
+    ip: String,
+    before: Option<i64>,
+}
+
+#[get("/admin/audit")]
+async fn audit_get(
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    query: web::Query<AuditQuery>,
+) -> impl Responder {
+    let query = query.into_inner();
+    let non_empty = |x: &str| Some(x.trim().to_owned()).filter(|x| !x.is_empty());
+    let filter = AuditFilter {
+        event: query.event.parse().ok(),
+        user: non_empty(&query.user),
+        ip: non_empty(&query.ip),
+        before: query.before,
+    };
+
+    let records = data.db.get_audit_log(&filter, AUDIT_PAGE_SIZE).await;
+
+    // the next page continues where this one ended, with the same filters
+    let older = records
+        .last()
+        .filter(|_| records.len() as i64 == AUDIT_PAGE_SIZE)
+        .map(|last| {
+            serde_urlencoded::to_string(AuditQuery {
+                event: query.event.clone(),
+                user: query.user.clone(),
+                ip: query.ip.clone(),
+                before: Some(last.id),
+            })
+            .unwrap()
+        });
+
+    let records = records
+        .into_iter()
+        .map(|record| AuditView {
+            at: format_timestamp(record.at),
+            event: record.event,
+            actor: match (record.actor_name, record.actor_id) {
+                (Some(name), _) => name,
+                (None, Some(id)) => format!("#{id}"),
+                (None, None) => "-".into(),
+            },
+            subject: record.subject,
+            ip: record.ip.unwrap_or_else(|| "-".into()),
+            user_agent: record.user_agent.unwrap_or_else(|| "-".into()),
+        })
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            AuditTemplate {
+                records,
+                events: AuditEvent::ALL
+                    .into_iter()
+                    .map(|event| EventOption {
+                        name: event.as_str(),
+                        selected: filter.event == Some(event),
+                    })
+                    .collect(),
+                query,
+                older,
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Debug, Clone)]
+pub struct ManagedUser {
+    pub id: i64,
+    pub name: String,
+    pub disabled: bool,
+    pub roles: Vec<i64>,
+}
+
+struct RoleCheckbox {
+    id: i64,
+    name: String,
+    checked: bool,
+}
+
+struct UserView {
+    id: i64,
+    name: String,
+    disabled: bool,
+    roles: Vec<RoleCheckbox>,
+    is_self: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_users.html")]
+struct UsersTemplate {
+    newreset: Option<NewResetTicket>,
+    error: Option<&'static str>,
+    users: Vec<UserView>,
+    csrf_token: String,
+}
+
+async fn render_users(
+    data: &crate::AppData,
+    csrf: &CsrfToken,
+    info: &UserInfo,
+    newreset: Option<NewResetTicket>,
+    error: Option<&'static str>,
+) -> String {
+    let roles = data.db.get_roles().await;
+    let users = data
+        .db
+        .get_users()
+        .await
+        .into_iter()
+        .map(|user| UserView {
+            is_self: user.id == info.id,
+            id: user.id,
+            name: user.name,
+            disabled: user.disabled,
+            roles: roles
+                .iter()
+                .map(|role| RoleCheckbox {
+                    id: role.id,
+                    name: role.name.clone(),
+                    checked: user.roles.contains(&role.id),
+                })
+                .collect(),
+        })
+        .collect();
+
+    UsersTemplate {
+        newreset,
+        error,
+        users,
+        csrf_token: csrf.get().to_owned(),
+    }
+    .to_string()
+}
+
+fn users_error(body: String) -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+        .content_type(ContentType::html())
+        .body(body)
+}
+
+fn users_redirect() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", "/admin/users"))
+        .finish()
+}
+
+#[get("/admin/users")]
+async fn users_get(
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    session: Session,
+) -> impl Responder {
+    let newreset = session
+        .remove_as::<NewResetTicket>(crate::session_keys::NEW_RESET_TICKET)
+        .map(Result::unwrap);
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(render_users(&data, &csrf, &info, newreset, None).await)
+}
+
+/// The form has an `id` field, and a `role` field for every checked role.
+fn parse_roles_form(form: &[(String, String)]) -> Option<(i64, Vec<i64>)> {
+    let mut id = None;
+    let mut roles = vec![];
+    for (key, value) in form {
+        match key.as_str() {
+            "id" => id = Some(value.parse().ok()?),
+            "role" => roles.push(value.parse().ok()?),
+            _ => {}
+        }
+    }
+
+    Some((id?, roles))
+}
+
+#[post("/admin/users/roles")]
+async fn set_roles(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<Vec<(String, String)>>,
+) -> impl Responder {
+    let Some((id, role_ids)) = parse_roles_form(&form) else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    };
+
+    let roles: Vec<Role> = data
+        .db
+        .get_roles()
+        .await
+        .into_iter()
+        .filter(|role| role_ids.contains(&role.id))
+        .collect();
+
+    // otherwise the last admin could lock everyone out of the admin pages
+    if id == info.id && !roles.iter().any(|role| role.capabilities.is_admin()) {
+        return users_error(
+            render_users(
+                &data,
+                &csrf,
+                &info,
+                None,
+                Some("You can't remove your own admin permission."),
+            )
+            .await,
+        );
+    }
+
+    if let Some(name) = data.db.get_username(id).await {
+        if data.db.set_user_roles(id, &role_ids).await {
+            data.user_cache.invalidate(id);
+            let roles = roles
+                .iter()
+                .map(|role| role.name.as_str())
+                .collect::<Vec<_>>()
+                .join(", ");
+            data.db
+                .audit(
+                    AuditEvent::PermissionsChanged,
+                    Some(info.id),
+                    &format!("{name}: roles [{roles}]"),
+                    &RequestOrigin::of(&req),
+                )
+                .await;
+
+            return users_redirect();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+#[derive(Deserialize)]
+struct RenameForm {
+    id: i64,
+    name: String,
+}
+
+#[post("/admin/users/rename")]
+async fn rename_user(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<RenameForm>,
+) -> impl Responder {
+    let Some(old_name) = data.db.get_username(form.id).await else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    if crate::auth::verify_username(&form.name) && data.db.rename_user(form.id, &form.name).await {
+        data.user_cache.invalidate(form.id);
+        data.db
+            .audit(
+                AuditEvent::UserRenamed,
+                Some(info.id),
+                &format!("{old_name} -> {}", form.name),
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return users_redirect();
+    }
+
+    users_error(
+        render_users(
+            &data,
+            &csrf,
+            &info,
+            None,
+            Some(
+                "Couldn't rename the user. Please make sure that the name is valid, \
+                and that it isn't already taken by a user or a ticket.",
+            ),
+        )
+        .await,
+    )
+}
+
+#[derive(Deserialize)]
+struct DisableForm {
+    id: i64,
+    disabled: bool,
+}
+
+#[post("/admin/users/disable")]
+async fn set_disabled(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<DisableForm>,
+) -> impl Responder {
+    if form.id == info.id {
+        return users_error(
+            render_users(
+                &data,
+                &csrf,
+                &info,
+                None,
+                Some("You can't disable your own account."),
+            )
+            .await,
+        );
+    }
+
+    if let Some(name) = data.db.get_username(form.id).await {
+        if data.db.set_disabled(form.id, form.disabled).await {
+            data.user_cache.invalidate(form.id);
+            let event = if form.disabled {
+                AuditEvent::UserDisabled
+            } else {
+                AuditEvent::UserEnabled
+            };
+            data.db
+                .audit(event, Some(info.id), &name, &RequestOrigin::of(&req))
+                .await;
+
+            return users_redirect();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+/// A reset ticket that was just issued, shown once on the users page.
+#[derive(Serialize, Deserialize)]
+struct NewResetTicket {
+    name: String,
+    url: String,
+}
+
+/// How long a password reset ticket stays valid.
+const RESET_TICKET_LIFETIME: i64 = 24 * 60 * 60; // seconds
+
+#[derive(Deserialize)]
+struct ResetPasswordForm {
+    id: i64,
+}
+
+#[post("/admin/users/reset")]
+async fn reset_password(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    form: web::Form<ResetPasswordForm>,
+    session: Session,
+) -> impl Responder {
+    if let Some(name) = data.db.get_username(form.id).await {
+        let expires_at = unix_now() + RESET_TICKET_LIFETIME;
+        if let Some(ticket) = data
+            .db
+            .generate_reset_ticket(form.id, Some(info.id), expires_at)
+            .await
+        {
+            data.db
+                .audit(
+                    AuditEvent::PasswordResetIssued,
+                    Some(info.id),
+                    &name,
+                    &RequestOrigin::of(&req),
+                )
+                .await;
+            session
+                .insert(
+                    crate::session_keys::NEW_RESET_TICKET,
+                    NewResetTicket {
+                        url: reset_url(&req, &ticket),
+                        name,
+                    },
+                )
+                .unwrap();
+
+            return users_redirect();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+struct CapabilityCheckbox {
+    name: &'static str,
+    checked: bool,
+}
+
+struct RoleView {
+    id: i64,
+    name: String,
+    builtin: bool,
+    capabilities: Vec<CapabilityCheckbox>,
+}
+
+#[derive(Template)]
+#[template(path = "admin_roles.html")]
+struct RolesTemplate {
+    error: Option<&'static str>,
+    roles: Vec<RoleView>,
+    capabilities: Vec<&'static str>,
+    csrf_token: String,
+}
+
+async fn render_roles(
+    data: &crate::AppData,
+    csrf: &CsrfToken,
+    error: Option<&'static str>,
+) -> String {
+    let roles = data
+        .db
+        .get_roles()
+        .await
+        .into_iter()
+        .map(|role| RoleView {
+            id: role.id,
+            builtin: is_builtin_role(&role.name),
+            name: role.name,
+            capabilities: Capability::ALL
+                .into_iter()
+                .map(|capability| CapabilityCheckbox {
+                    name: capability.as_str(),
+                    checked: role.capabilities.has(capability),
+                })
+                .collect(),
+        })
+        .collect();
+
+    RolesTemplate {
+        error,
+        roles,
+        capabilities: Capability::ALL.iter().map(Capability::as_str).collect(),
+        csrf_token: csrf.get().to_owned(),
+    }
+    .to_string()
+}
+
+fn roles_redirect() -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", "/admin/roles"))
+        .finish()
+}
+
+/// Collects the `capability` fields of a form. Unknown capabilities are rejected.
+fn parse_capabilities(form: &[(String, String)]) -> Option<UserPermissions> {
+    form.iter()
+        .filter(|(key, _)| key == "capability")
+        .map(|(_, value)| value.parse().ok())
+        .collect()
+}
+
+fn form_field<'a>(form: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    form.iter()
+        .find(|(key, _)| key == name)
+        .map(|(_, value)| value.as_str())
+}
+
+#[get("/admin/roles")]
+async fn roles_get(
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(render_roles(&data, &csrf, None).await)
+}
+
+#[post("/admin/roles")]
+async fn create_role(
+    data: Data<crate::AppData>,
+    _admin: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<Vec<(String, String)>>,
+) -> impl Responder {
+    if let (Some(name), Some(capabilities)) = (form_field(&form, "name"), parse_capabilities(&form))
+    {
+        // role names follow the same rules as usernames
+        if crate::auth::verify_username(name) && data.db.create_role(name, capabilities).await {
+            return roles_redirect();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+        .content_type(ContentType::html())
+        .body(
+            render_roles(
+                &data,
+                &csrf,
+                Some(
+                    "Couldn't create the role. Please make sure that the name is valid, \
+                    and that it isn't already taken by another role.",
+                ),
+            )
+            .await,
+        )
+}
+
+/// The admin role can't be changed, so that admins can't lock themselves out, and the short role
+/// is granted by name when a ticket is redeemed.
+fn is_builtin_role(name: &str) -> bool {
+    name == ADMIN_ROLE || name == SHORT_ROLE
+}
+
+/// Looks up a role that may be changed, which is every role but the built-in ones.
+async fn editable_role(data: &crate::AppData, form: &[(String, String)]) -> Option<Role> {
+    let id: i64 = form_field(form, "id")?.parse().ok()?;
+    data.db
+        .get_roles()
+        .await
+        .into_iter()
+        .find(|role| role.id == id && !is_builtin_role(&role.name))
+}
+
+/// Whether the admin would still be an admin after `role` is given `capabilities`,
+/// or deleted when that's `None`.
+async fn keeps_admin(
+    data: &crate::AppData,
+    admin: i64,
+    role: &Role,
+    capabilities: Option<UserPermissions>,
+) -> bool {
+    let held = data.db.get_role_ids(admin).await;
+    data.db
+        .get_roles()
+        .await
+        .into_iter()
+        .filter(|other| held.contains(&other.id))
+        .any(|other| {
+            if other.id == role.id {
+                capabilities.is_some_and(|capabilities| capabilities.is_admin())
+            } else {
+                other.capabilities.is_admin()
+            }
+        })
+}
+
+fn roles_error(body: String) -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+        .content_type(ContentType::html())
+        .body(body)
+}
+
+#[post("/admin/roles/capabilities")]
+async fn set_role_capabilities(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<Vec<(String, String)>>,
+) -> impl Responder {
+    if let (Some(role), Some(capabilities)) =
+        (editable_role(&data, &form).await, parse_capabilities(&form))
+    {
+        // the admin role isn't the only way to be an admin
+        if !keeps_admin(&data, info.id, &role, Some(capabilities)).await {
+            return roles_error(
+                render_roles(
+                    &data,
+                    &csrf,
+                    Some("You can't remove your own admin permission."),
+                )
+                .await,
+            );
+        }
+
+        data.db.set_role_capabilities(role.id, capabilities).await;
+        data.user_cache.clear();
+
+        let capabilities = capabilities
+            .capabilities()
+            .map(|x| x.as_str())
+            .collect::<Vec<_>>()
+            .join(", ");
+        data.db
+            .audit(
+                AuditEvent::PermissionsChanged,
+                Some(info.id),
+                &format!("role {}: capabilities [{capabilities}]", role.name),
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return roles_redirect();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+#[post("/admin/roles/delete")]
+async fn delete_role(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<Vec<(String, String)>>,
+) -> impl Responder {
+    if let Some(role) = editable_role(&data, &form).await {
+        if !keeps_admin(&data, info.id, &role, None).await {
+            return roles_error(
+                render_roles(
+                    &data,
+                    &csrf,
+                    Some("You can't remove your own admin permission."),
+                )
+                .await,
+            );
+        }
+
+        if data.db.delete_role(role.id).await {
+            data.user_cache.clear();
+            data.db
+                .audit(
+                    AuditEvent::PermissionsChanged,
+                    Some(info.id),
+                    &format!("role {}: deleted", role.name),
+                    &RequestOrigin::of(&req),
+                )
+                .await;
+
+            return roles_redirect();
+        }
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+struct InviteNode {
+    id: i64,
+    name: String,
+    /// How deep in the tree the user is, 0 for users nobody invited.
+    depth: usize,
+    disabled: bool,
+    pending: String,
+    /// Whether the admin viewing the page is part of this user's branch, which they can't revoke.
+    contains_self: bool,
+}
+
+#[derive(Template)]
+#[template(path = "admin_invites.html")]
+struct InvitesTemplate {
+    error: Option<&'static str>,
+    nodes: Vec<InviteNode>,
+    csrf_token: String,
+}
+
+/// Lays the invite tree out depth-first, so that every user comes right after their inviter.
+fn flatten_invite_tree(
+    users: Vec<InvitedUser>,
+    mut pending: HashMap<String, Vec<String>>,
+    viewer: i64,
+) -> Vec<InviteNode> {
+    let ids: HashSet<i64> = users.iter().map(|user| user.id).collect();
+    let parents: HashMap<i64, i64> = users
+        .iter()
+        .filter_map(|user| Some((user.id, user.invited_by?)))
+        .collect();
+
+    // the viewer's branch is contained in the branch of every one of their inviters
+    let mut ancestors = HashSet::from([viewer]);
+    let mut current = viewer;
+    while let Some(&parent) = parents.get(&current) {
+        if !ancestors.insert(parent) {
+            break;
+        }
+        current = parent;
+    }
+
+    let mut roots = vec![];
+    let mut children: HashMap<i64, Vec<InvitedUser>> = HashMap::new();
+    for user in users {
+        match user.invited_by.filter(|id| ids.contains(id)) {
+            Some(parent) => children.entry(parent).or_default().push(user),
+            None => roots.push(user),
+        }
+    }
+
+    let mut nodes = vec![];
+    let mut stack: Vec<(InvitedUser, usize)> =
+        roots.into_iter().rev().map(|user| (user, 0)).collect();
+    while let Some((user, depth)) = stack.pop() {
+        if let Some(invitees) = children.remove(&user.id) {
+            stack.extend(invitees.into_iter().rev().map(|user| (user, depth + 1)));
+        }
+
+        nodes.push(InviteNode {
+            pending: pending
+                .remove(&user.name)
+                .map(|names| names.join(", "))
+                .unwrap_or_else(|| "-".into()),
+            contains_self: ancestors.contains(&user.id),
+            id: user.id,
+            name: user.name,
+            depth,
+            disabled: user.disabled,
+        });
+    }
+
+    nodes
+}
+
+async fn render_invites(
+    data: &crate::AppData,
+    csrf: &CsrfToken,
+    info: &UserInfo,
+    error: Option<&'static str>,
+) -> String {
+    let mut pending: HashMap<String, Vec<String>> = HashMap::new();
+    for ticket in data.db.get_registration_tickets().await {
+        if let Some(issued_by) = ticket.issued_by {
+            pending.entry(issued_by).or_default().push(ticket.name);
+        }
+    }
+
+    InvitesTemplate {
+        error,
+        nodes: flatten_invite_tree(data.db.get_invite_tree().await, pending, info.id),
+        csrf_token: csrf.get().to_owned(),
+    }
+    .to_string()
+}
+
+#[get("/admin/invites")]
+async fn invites_get(
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(render_invites(&data, &csrf, &info, None).await)
+}
+
+#[derive(Deserialize)]
+struct RevokeBranchForm {
+    id: i64,
+}
+
+/// Disables the user along with everyone they invited, directly or indirectly,
+/// and revokes all of their outstanding tickets.
+#[post("/admin/invites/revoke")]
+async fn revoke_invite_branch(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    info: Hidden<RequirePermission<Admin>>,
+    csrf: CsrfToken,
+    form: web::Form<RevokeBranchForm>,
+) -> impl Responder {
+    let branch = data.db.get_invite_branch(form.id).await;
+    if branch.is_empty() {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    }
+
+    if branch.iter().any(|(id, _)| *id == info.id) {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+            .content_type(ContentType::html())
+            .body(
+                render_invites(
+                    &data,
+                    &csrf,
+                    &info,
+                    Some("You can't revoke a branch that contains your own account."),
+                )
+                .await,
+            );
+    }
+
+    let ids = branch.iter().map(|(id, _)| *id).collect::<Vec<_>>();
+    let tickets = data.db.revoke_invite_branch(&ids).await;
+
+    let origin = RequestOrigin::of(&req);
+    for (id, name) in &branch {
+        data.user_cache.invalidate(*id);
+        data.db
+            .audit(AuditEvent::UserDisabled, Some(info.id), name, &origin)
+            .await;
+    }
+    for name in &tickets {
+        data.db
+            .audit(AuditEvent::TicketRevoked, Some(info.id), name, &origin)
+            .await;
+    }
+
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", "/admin/invites"))
+        .finish()
+}
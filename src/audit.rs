@@ -0,0 +1,140 @@
+use std::str::FromStr;
+
+use actix_web::http::header;
+use actix_web::HttpRequest;
+
+/// Longest user agent kept in the audit log, so that clients can't bloat it.
+const USER_AGENT_MAX_LENGTH: usize = 256; // bytes
+
+/// Security-relevant events that are recorded in the audit log.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuditEvent {
+    Login,
+    LoginFailed,
+    Logout,
+    Register,
+    TicketIssued,
+    TicketRevoked,
+    PasswordResetIssued,
+    PasswordReset,
+    IdentityLinked,
+    PermissionsChanged,
+    UserRenamed,
+    UserDisabled,
+    UserEnabled,
+    LinkDeleted,
+    AccountDeleted,
+    PasswordChanged,
+    TotpEnabled,
+    TotpDisabled,
+    PasskeyRegistered,
+    PasskeyDeleted,
+}
+
+impl AuditEvent {
+    pub const ALL: [Self; 20] = [
+        Self::Login,
+        Self::LoginFailed,
+        Self::Logout,
+        Self::Register,
+        Self::TicketIssued,
+        Self::TicketRevoked,
+        Self::PasswordResetIssued,
+        Self::PasswordReset,
+        Self::IdentityLinked,
+        Self::PermissionsChanged,
+        Self::UserRenamed,
+        Self::UserDisabled,
+        Self::UserEnabled,
+        Self::LinkDeleted,
+        Self::AccountDeleted,
+        Self::PasswordChanged,
+        Self::TotpEnabled,
+        Self::TotpDisabled,
+        Self::PasskeyRegistered,
+        Self::PasskeyDeleted,
+    ];
+
+    /// The event's name, as stored in the DB.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Login => "login",
+            Self::LoginFailed => "login_failed",
+            Self::Logout => "logout",
+            Self::Register => "register",
+            Self::TicketIssued => "ticket_issued",
+            Self::TicketRevoked => "ticket_revoked",
+            Self::PasswordResetIssued => "password_reset_issued",
+            Self::PasswordReset => "password_reset",
+            Self::IdentityLinked => "identity_linked",
+            Self::PermissionsChanged => "permissions_changed",
+            Self::UserRenamed => "user_renamed",
+            Self::UserDisabled => "user_disabled",
+            Self::UserEnabled => "user_enabled",
+            Self::LinkDeleted => "link_deleted",
+            Self::AccountDeleted => "account_deleted",
+            Self::PasswordChanged => "password_changed",
+            Self::TotpEnabled => "totp_enabled",
+            Self::TotpDisabled => "totp_disabled",
+            Self::PasskeyRegistered => "passkey_registered",
+            Self::PasskeyDeleted => "passkey_deleted",
+        }
+    }
+}
+
+impl FromStr for AuditEvent {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|event| event.as_str() == s)
+            .ok_or(())
+    }
+}
+
+/// Where the request that caused an event came from.
+#[derive(Clone, Debug, Default)]
+pub struct RequestOrigin {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+impl RequestOrigin {
+    pub fn of(req: &HttpRequest) -> Self {
+        let user_agent = req
+            .headers()
+            .get(header::USER_AGENT)
+            // to_str only succeeds for ASCII, so any length is a valid cut
+            .and_then(|x| x.to_str().ok())
+            .map(|x| x[..x.len().min(USER_AGENT_MAX_LENGTH)].to_owned());
+
+        Self {
+            ip: req.peer_addr().map(|x| x.ip().to_string()),
+            user_agent,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub id: i64,
+    pub at: i64,
+    pub event: String,
+    pub actor_id: Option<i64>,
+    pub actor_name: Option<String>,
+    pub subject: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Narrows down which records are shown, all given conditions must hold.
+#[derive(Clone, Debug, Default)]
+pub struct AuditFilter {
+    pub event: Option<AuditEvent>,
+    /// Matches either the actor's name, or the event's subject.
+    pub user: Option<String>,
+    pub ip: Option<String>,
+    /// Only records older than this id, for paging.
+    pub before: Option<i64>,
+}
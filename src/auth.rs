@@ -1,50 +1,114 @@
 use actix_session::Session;
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{self, ContentType};
 use actix_web::http::StatusCode;
 use actix_web::web::{self, Data, ReqData};
-use actix_web::{get, post, HttpRequest, HttpResponseBuilder, Responder};
+use actix_web::{get, post, HttpRequest, HttpResponse, HttpResponseBuilder, Responder};
 use askama::Template;
 use serde::{Deserialize, Serialize};
 
+use crate::audit::{AuditEvent, RequestOrigin};
 use crate::auth::middleware::Login;
+use crate::auth::throttle::ThrottleKey;
+use crate::csrf::CsrfToken;
+use crate::db::{unix_now, UserPermissions};
 
+pub mod guard;
 pub mod middleware;
+pub mod oidc;
+pub mod passkey;
+pub mod throttle;
+pub mod totp;
 
 #[derive(Template)]
 #[template(path = "login.html")]
 struct LoginTemplate {
     failed: bool,
+    passkeys: bool,
+    /// The name of the external login provider, if there is one.
+    oidc: Option<String>,
+    retry_after: Option<i64>,
+    csrf_token: String,
+    next: String,
 }
 
 #[derive(Template)]
 #[template(path = "register.html")]
 struct RegisterTemplate {
     failed: bool,
+    weak_password: Option<&'static str>,
+    ticket: String,
+    retry_after: Option<i64>,
+    csrf_token: String,
+    next: String,
+}
+
+#[derive(Template)]
+#[template(path = "reset.html")]
+struct ResetTemplate {
+    failed: bool,
+    weak_password: Option<&'static str>,
+    ticket: String,
+    retry_after: Option<i64>,
+    csrf_token: String,
 }
 
 #[get("/login")]
-async fn login_get(login: ReqData<Login>) -> impl Responder {
+async fn login_get(
+    data: Data<crate::AppData>,
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+    query: web::Query<NextQuery>,
+) -> impl Responder {
     if login.info().is_some() {
-        HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-            .insert_header(("Location", "/"))
-            .finish()
+        after_login(&query.next)
     } else {
         HttpResponseBuilder::new(StatusCode::OK)
             .content_type(ContentType::html())
-            .body(LoginTemplate { failed: false }.to_string())
+            .body(
+                LoginTemplate {
+                    failed: false,
+                    passkeys: data.webauthn.is_some(),
+                    oidc: data.oidc.as_ref().map(|oidc| oidc.name().to_owned()),
+                    retry_after: None,
+                    csrf_token: csrf.get().to_owned(),
+                    next: next_field(&query.next),
+                }
+                .to_string(),
+            )
     }
 }
 
+#[derive(Deserialize)]
+struct RegisterGetQuery {
+    #[serde(default)]
+    ticket: String,
+    #[serde(default)]
+    next: String,
+}
+
 #[get("/register")]
-async fn register_get(login: ReqData<Login>) -> impl Responder {
+async fn register_get(
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+    query: web::Query<RegisterGetQuery>,
+) -> impl Responder {
     if login.info().is_some() {
-        HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-            .insert_header(("Location", "/"))
-            .finish()
+        after_login(&query.next)
     } else {
+        let query = query.into_inner();
         HttpResponseBuilder::new(StatusCode::OK)
             .content_type(ContentType::html())
-            .body(RegisterTemplate { failed: false }.to_string())
+            .body(
+                RegisterTemplate {
+                    failed: false,
+                    weak_password: None,
+                    ticket: query.ticket,
+                    retry_after: None,
+                    csrf_token: csrf.get().to_owned(),
+                    next: next_field(&query.next),
+                }
+                .to_string(),
+            )
     }
 }
 
@@ -52,54 +116,348 @@ async fn register_get(login: ReqData<Login>) -> impl Responder {
 struct LoginForm {
     username: String,
     password: String,
+    #[serde(default)]
+    next: String,
 }
 
-fn verify_username(username: &str) -> bool {
+#[derive(Deserialize)]
+pub struct NextQuery {
+    #[serde(default)]
+    pub next: String,
+}
+
+pub fn verify_username(username: &str) -> bool {
     (1..=64).contains(&username.len())
         && username
             .bytes()
             .all(|b| b.is_ascii_alphanumeric() || b == b'_')
 }
 
-fn verify_password(password: &str) -> bool {
+pub fn verify_password(password: &str) -> bool {
     (8..=64).contains(&password.len())
 }
 
+/// Why a new password was rejected.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WeakPassword {
+    Length,
+    Common,
+    LikeUsername,
+}
+
+impl WeakPassword {
+    pub fn message(&self) -> &'static str {
+        match self {
+            Self::Length => "Password must be between 8-64 characters.",
+            Self::Common => {
+                "This password is too common, and would be among the first ones \
+                an attacker tries. Please choose another one."
+            }
+            Self::LikeUsername => {
+                "This password is too similar to the username. Please choose another one."
+            }
+        }
+    }
+}
+
+/// Checks a password that is about to be set for `username`. Existing passwords are only held
+/// to [`verify_password`], so that nobody gets locked out.
+pub fn check_new_password(
+    data: &crate::AppData,
+    username: &str,
+    password: &str,
+) -> Result<(), WeakPassword> {
+    if !verify_password(password) {
+        Err(WeakPassword::Length)
+    } else if data.common_passwords.contains(&password.to_lowercase()) {
+        Err(WeakPassword::Common)
+    } else if resembles_username(password, username) {
+        Err(WeakPassword::LikeUsername)
+    } else {
+        Ok(())
+    }
+}
+
+/// Usernames shorter than this show up in plenty of good passwords by chance.
+const RESEMBLANCE_MIN_LENGTH: usize = 3; // characters
+
+/// Whether the password is little more than the username, like `alice123` or `ecila!` for `alice`.
+fn resembles_username(password: &str, username: &str) -> bool {
+    let password = password.to_lowercase();
+    let username = username.to_lowercase();
+    if username.chars().count() < RESEMBLANCE_MIN_LENGTH {
+        return false;
+    }
+    let reversed = username.chars().rev().collect::<String>();
+
+    // whatever is left once the username is taken out has to hold up as a password by itself
+    username.contains(&password)
+        || [username.as_str(), reversed.as_str()]
+            .into_iter()
+            .any(|name| password.contains(name) && password.replace(name, "").len() < 8)
+}
+
+const NEXT_MAX_LENGTH: usize = 1024; // bytes
+
+/// Checks that `next` is a path on this site, so that it can't be used for open redirects.
+/// Browsers treat `//host` and `/\host` as links to another host, and skip tabs and
+/// newlines, so backslashes and control characters are rejected anywhere.
+pub fn safe_next(next: &str) -> Option<&str> {
+    (next.len() <= NEXT_MAX_LENGTH
+        && next.starts_with('/')
+        && !next.starts_with("//")
+        && !next.chars().any(|c| c == '\\' || c.is_control()))
+    .then_some(next)
+}
+
+/// The `next` value to carry through a form, or nothing if it isn't safe.
+fn next_field(next: &str) -> String {
+    safe_next(next).unwrap_or_default().to_owned()
+}
+
+/// Sends a user who just signed in to the page they were after, or the index.
+fn after_login(next: &str) -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", safe_next(next).unwrap_or("/")))
+        .finish()
+}
+
+/// Sends a user whose login is pending to enter their second factor, keeping `next`.
+fn second_factor_redirect(next: &str) -> HttpResponse {
+    let location = match safe_next(next) {
+        Some(next) => format!(
+            "/login/2fa?{}",
+            serde_urlencoded::to_string([("next", next)]).unwrap()
+        ),
+        None => "/login/2fa".to_owned(),
+    };
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", location))
+        .finish()
+}
+
+/// Answers an attempt made while locked out, without checking any credentials.
+fn too_many_attempts(retry_after: i64, body: String) -> HttpResponse {
+    HttpResponseBuilder::new(StatusCode::TOO_MANY_REQUESTS)
+        .insert_header((header::RETRY_AFTER, retry_after))
+        .content_type(ContentType::html())
+        .body(body)
+}
+
 #[post("/login")]
 async fn login_post(
+    req: HttpRequest,
     data: Data<crate::AppData>,
     form: web::Form<LoginForm>,
     session: Session,
     login: ReqData<Login>,
+    csrf: CsrfToken,
 ) -> impl Responder {
     if login.info().is_some() {
-        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-            .insert_header(("Location", "/"))
-            .finish();
+        return after_login(&form.next);
+    }
+
+    let throttle = &data.state.login_throttle;
+    let mut keys: Vec<_> = ThrottleKey::ip(&req).into_iter().collect();
+    if verify_username(&form.username) {
+        keys.extend(ThrottleKey::username(&req, &form.username));
+    }
+
+    if let Some(retry_after) = throttle.check(&keys) {
+        return too_many_attempts(
+            retry_after,
+            LoginTemplate {
+                failed: false,
+                passkeys: data.webauthn.is_some(),
+                oidc: data.oidc.as_ref().map(|oidc| oidc.name().to_owned()),
+                retry_after: Some(retry_after),
+                csrf_token: csrf.get().to_owned(),
+                next: next_field(&form.next),
+            }
+            .to_string(),
+        );
     }
 
     if verify_username(&form.username) && verify_password(&form.password) {
         if let Some(id) = data.db.verify_user(&form.username, &form.password).await {
+            if let Some(key) = ThrottleKey::username(&req, &form.username) {
+                throttle.clear(&key);
+            }
+
+            // logins that need a second factor are recorded once it's been entered
+            if data.db.totp_enabled(id).await {
+                login.login_pending(id);
+                return second_factor_redirect(&form.next);
+            }
+
+            data.db
+                .audit(
+                    AuditEvent::Login,
+                    Some(id),
+                    &form.username,
+                    &RequestOrigin::of(&req),
+                )
+                .await;
             session
                 .insert(crate::session_keys::SUCCESSFUL, "logged in")
                 .unwrap();
             login.login(id);
 
-            return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-                .insert_header(("Location", "/"))
-                .finish();
+            return after_login(&form.next);
         }
     }
 
+    throttle.fail(&keys);
+    // the username is only recorded if it's valid, so that the log can't be stuffed with junk
+    let subject = if verify_username(&form.username) {
+        form.username.as_str()
+    } else {
+        "(invalid username)"
+    };
+    data.db
+        .audit(
+            AuditEvent::LoginFailed,
+            None,
+            subject,
+            &RequestOrigin::of(&req),
+        )
+        .await;
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            LoginTemplate {
+                failed: true,
+                passkeys: data.webauthn.is_some(),
+                oidc: data.oidc.as_ref().map(|oidc| oidc.name().to_owned()),
+                retry_after: None,
+                csrf_token: csrf.get().to_owned(),
+                next: next_field(&form.next),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Template)]
+#[template(path = "login_2fa.html")]
+struct SecondFactorTemplate {
+    failed: bool,
+    retry_after: Option<i64>,
+    csrf_token: String,
+    next: String,
+}
+
+#[get("/login/2fa")]
+async fn second_factor_get(
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+    query: web::Query<NextQuery>,
+) -> impl Responder {
+    if login.pending().is_some() {
+        HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(ContentType::html())
+            .body(
+                SecondFactorTemplate {
+                    failed: false,
+                    retry_after: None,
+                    csrf_token: csrf.get().to_owned(),
+                    next: next_field(&query.next),
+                }
+                .to_string(),
+            )
+    } else {
+        HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/login"))
+            .finish()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+struct SecondFactorForm {
+    code: String,
+    #[serde(default)]
+    next: String,
+}
+
+#[post("/login/2fa")]
+async fn second_factor_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<SecondFactorForm>,
+    session: Session,
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+) -> impl Responder {
+    let Some(id) = login.pending() else {
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/login"))
+            .finish();
+    };
+
+    // codes are short, so guessing them is throttled just like guessing passwords,
+    // and per user as well, since the password was already enough to get here
+    let throttle = &data.state.login_throttle;
+    let mut keys: Vec<_> = ThrottleKey::ip(&req).into_iter().collect();
+    keys.push(ThrottleKey::SecondFactor(id));
+    if let Some(retry_after) = throttle.check(&keys) {
+        return too_many_attempts(
+            retry_after,
+            SecondFactorTemplate {
+                failed: false,
+                retry_after: Some(retry_after),
+                csrf_token: csrf.get().to_owned(),
+                next: next_field(&form.next),
+            }
+            .to_string(),
+        );
+    }
+
+    let name = data.db.get_username(id).await.unwrap_or_default();
+    let code = form.code.trim();
+    if code.len() <= 32
+        && (data.db.check_totp(id, code).await || data.db.use_recovery_code(id, code).await)
+    {
+        throttle.clear(&ThrottleKey::SecondFactor(id));
+        data.db
+            .audit(AuditEvent::Login, Some(id), &name, &RequestOrigin::of(&req))
+            .await;
+        session
+            .insert(crate::session_keys::SUCCESSFUL, "logged in")
+            .unwrap();
+        login.login(id);
+
+        return after_login(&form.next);
+    }
+
+    throttle.fail(&keys);
+    data.db
+        .audit(
+            AuditEvent::LoginFailed,
+            Some(id),
+            &name,
+            &RequestOrigin::of(&req),
+        )
+        .await;
+
     HttpResponseBuilder::new(StatusCode::FORBIDDEN)
         .content_type(ContentType::html())
-        .body(LoginTemplate { failed: true }.to_string())
+        .body(
+            SecondFactorTemplate {
+                failed: true,
+                retry_after: None,
+                csrf_token: csrf.get().to_owned(),
+                next: next_field(&form.next),
+            }
+            .to_string(),
+        )
 }
 
 #[derive(Serialize, Deserialize)]
 struct RegisterForm {
     password: String,
     ticket: String,
+    #[serde(default)]
+    next: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -109,25 +467,188 @@ struct RegisterQuery {
 
 #[post("/register")]
 async fn register_post(
+    req: HttpRequest,
     data: Data<crate::AppData>,
     form: Option<web::Form<RegisterForm>>,
     session: Session,
     login: ReqData<Login>,
+    csrf: CsrfToken,
 ) -> impl Responder {
+    let next = form
+        .as_ref()
+        .map(|form| next_field(&form.next))
+        .unwrap_or_default();
+
     if login.info().is_some() {
-        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-            .insert_header(("Location", "/"))
-            .finish();
+        return after_login(&next);
+    }
+
+    let throttle = &data.state.login_throttle;
+    let keys: Vec<_> = ThrottleKey::ip(&req).into_iter().collect();
+    if let Some(retry_after) = throttle.check(&keys) {
+        return too_many_attempts(
+            retry_after,
+            RegisterTemplate {
+                failed: false,
+                weak_password: None,
+                ticket: String::new(),
+                retry_after: Some(retry_after),
+                csrf_token: csrf.get().to_owned(),
+                next: next.clone(),
+            }
+            .to_string(),
+        );
     }
 
     if let Some(form) = form {
         if verify_password(&form.password) && form.ticket.len() <= 512 {
-            if let Some(id) = data.db.register_user(&form.ticket, &form.password).await {
+            // the ticket holder's username is only known once the ticket checks out
+            if let Some(name) = data.db.get_ticket_name(&form.ticket).await {
+                if let Err(weak) = check_new_password(&data, &name, &form.password) {
+                    return HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+                        .content_type(ContentType::html())
+                        .body(
+                            RegisterTemplate {
+                                failed: false,
+                                weak_password: Some(weak.message()),
+                                ticket: form.ticket.clone(),
+                                retry_after: None,
+                                csrf_token: csrf.get().to_owned(),
+                                next,
+                            }
+                            .to_string(),
+                        );
+                }
+            }
+
+            if let Some(id) = data
+                .db
+                .register_user(&form.ticket, &form.password, &RequestOrigin::of(&req))
+                .await
+            {
                 session
                     .insert(crate::session_keys::SUCCESSFUL, "registered")
                     .unwrap();
                 login.login(id);
 
+                return after_login(&next);
+            }
+        }
+    }
+
+    throttle.fail(&keys);
+
+    HttpResponseBuilder::new(StatusCode::FORBIDDEN)
+        .content_type(ContentType::html())
+        .body(
+            RegisterTemplate {
+                failed: true,
+                weak_password: None,
+                ticket: String::new(),
+                retry_after: None,
+                csrf_token: csrf.get().to_owned(),
+                next,
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Deserialize)]
+struct ResetGetQuery {
+    #[serde(default)]
+    ticket: String,
+}
+
+#[get("/reset")]
+async fn reset_get(
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+    query: web::Query<ResetGetQuery>,
+) -> impl Responder {
+    if login.info().is_some() {
+        return after_login("");
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            ResetTemplate {
+                failed: false,
+                weak_password: None,
+                ticket: query.into_inner().ticket,
+                retry_after: None,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize, Deserialize)]
+struct ResetForm {
+    password: String,
+    ticket: String,
+}
+
+#[post("/reset")]
+async fn reset_post(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: Option<web::Form<ResetForm>>,
+    session: Session,
+    login: ReqData<Login>,
+    csrf: CsrfToken,
+) -> impl Responder {
+    if login.info().is_some() {
+        return after_login("");
+    }
+
+    // reset tickets are guessed just like registration tickets, so they share the throttle
+    let throttle = &data.state.login_throttle;
+    let keys: Vec<_> = ThrottleKey::ip(&req).into_iter().collect();
+    if let Some(retry_after) = throttle.check(&keys) {
+        return too_many_attempts(
+            retry_after,
+            ResetTemplate {
+                failed: false,
+                weak_password: None,
+                ticket: String::new(),
+                retry_after: Some(retry_after),
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        );
+    }
+
+    if let Some(form) = form {
+        if verify_password(&form.password) && form.ticket.len() <= 512 {
+            if let Some(name) = data.db.get_reset_ticket_name(&form.ticket).await {
+                if let Err(weak) = check_new_password(&data, &name, &form.password) {
+                    return HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+                        .content_type(ContentType::html())
+                        .body(
+                            ResetTemplate {
+                                failed: false,
+                                weak_password: Some(weak.message()),
+                                ticket: form.ticket.clone(),
+                                retry_after: None,
+                                csrf_token: csrf.get().to_owned(),
+                            }
+                            .to_string(),
+                        );
+                }
+            }
+
+            if let Some(id) = data
+                .db
+                .reset_password(&form.ticket, &form.password, &RequestOrigin::of(&req))
+                .await
+            {
+                data.user_cache.invalidate(id);
+                session
+                    .insert(crate::session_keys::SUCCESSFUL, "reset your password")
+                    .unwrap();
+
+                // not logged in right away, so that a second factor can't be skipped
                 return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
                     .insert_header(("Location", "/"))
                     .finish();
@@ -135,11 +656,24 @@ async fn register_post(
         }
     }
 
+    throttle.fail(&keys);
+
     HttpResponseBuilder::new(StatusCode::FORBIDDEN)
         .content_type(ContentType::html())
-        .body(RegisterTemplate { failed: true }.to_string())
+        .body(
+            ResetTemplate {
+                failed: true,
+                weak_password: None,
+                ticket: String::new(),
+                retry_after: None,
+                csrf_token: csrf.get().to_owned(),
+            }
+            .to_string(),
+        )
 }
 
+const LOOPBACK_TICKET_LIFETIME: i64 = 7 * 24 * 60 * 60; // seconds
+
 #[post("/register/{name}")]
 async fn register_path_post(
     req: HttpRequest,
@@ -147,7 +681,20 @@ async fn register_path_post(
     path: web::Path<String>,
 ) -> impl Responder {
     if let Some(true) = req.peer_addr().map(|x| x.ip().is_loopback()) {
-        if let Some(ticket) = data.db.generate_registration_ticket(&path).await {
+        let expires_at = unix_now() + LOOPBACK_TICKET_LIFETIME;
+        if let Some(ticket) = data
+            .db
+            .generate_registration_ticket(&path, None, expires_at, UserPermissions::default())
+            .await
+        {
+            data.db
+                .audit(
+                    AuditEvent::TicketIssued,
+                    None,
+                    &path,
+                    &RequestOrigin::of(&req),
+                )
+                .await;
             HttpResponseBuilder::new(StatusCode::OK).body(ticket)
         } else {
             HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish()
@@ -158,7 +705,23 @@ async fn register_path_post(
 }
 
 #[post("/logout")]
-async fn logout(session: Session, login: ReqData<Login>) -> impl Responder {
+async fn logout(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    session: Session,
+    login: ReqData<Login>,
+) -> impl Responder {
+    if let Some(info) = login.info() {
+        data.db
+            .audit(
+                AuditEvent::Logout,
+                Some(info.id),
+                &info.name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+    }
+
     if login.logout() {
         session
             .insert(crate::session_keys::SUCCESSFUL, "logged out")
@@ -169,3 +732,68 @@ async fn logout(session: Session, login: ReqData<Login>) -> impl Responder {
         .insert_header(("Location", "/"))
         .finish()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resembles_username_catches_the_username_in_disguise() {
+        assert!(resembles_username("alice123", "alice"));
+        assert!(resembles_username("ALICE!!!!", "alice"));
+        assert!(resembles_username("ecila2024", "alice"));
+        assert!(resembles_username("alicealice", "alice"));
+        assert!(resembles_username("password", "my_password_is_good"));
+    }
+
+    #[test]
+    fn resembles_username_allows_unrelated_passwords() {
+        assert!(!resembles_username("correct horse battery", "alice"));
+        assert!(!resembles_username("alice in wonderland", "alice"));
+        assert!(!resembles_username("tr0ub4dor&3", "bob"));
+    }
+
+    #[test]
+    fn resembles_username_ignores_short_usernames() {
+        assert!(!resembles_username("elephant", "e"));
+        assert!(!resembles_username("abstract", "ab"));
+        assert!(resembles_username("bob12345", "bob"));
+    }
+
+    #[test]
+    fn safe_next_accepts_local_paths() {
+        assert_eq!(safe_next("/"), Some("/"));
+        assert_eq!(safe_next("/short"), Some("/short"));
+        assert_eq!(safe_next("/u/name?x=1#y"), Some("/u/name?x=1#y"));
+        // percent-encoded slashes are part of the path, browsers don't decode them
+        assert_eq!(safe_next("/%2F%2Fhost"), Some("/%2F%2Fhost"));
+    }
+
+    #[test]
+    fn safe_next_rejects_other_hosts() {
+        assert_eq!(safe_next("//host"), None);
+        assert_eq!(safe_next("/\\host"), None);
+        assert_eq!(safe_next("\\/host"), None);
+        assert_eq!(safe_next("https://host"), None);
+        assert_eq!(safe_next("javascript:alert(1)"), None);
+        assert_eq!(safe_next("host"), None);
+        assert_eq!(safe_next(""), None);
+    }
+
+    #[test]
+    fn safe_next_rejects_control_characters() {
+        assert_eq!(safe_next("\t//host"), None);
+        assert_eq!(safe_next("/\t/host"), None);
+        assert_eq!(safe_next("/\n/host"), None);
+        assert_eq!(safe_next("/\r\n/host"), None);
+    }
+
+    #[test]
+    fn safe_next_rejects_overlong_input() {
+        let longest = format!("/{}", "a".repeat(NEXT_MAX_LENGTH - 1));
+        assert_eq!(safe_next(&longest), Some(longest.as_str()));
+
+        let overlong = format!("/{}", "a".repeat(NEXT_MAX_LENGTH));
+        assert_eq!(safe_next(&overlong), None);
+    }
+}
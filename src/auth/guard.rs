@@ -0,0 +1,224 @@
+use std::marker::PhantomData;
+use std::ops::Deref;
+
+use actix_files::NamedFile;
+use actix_web::dev::Payload;
+use actix_web::error::InternalError;
+use actix_web::http::header::{self, HeaderMap};
+use actix_web::http::{Method, StatusCode};
+use actix_web::{Error, FromRequest, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use futures_util::future::LocalBoxFuture;
+use serde::Serialize;
+
+use crate::auth::middleware::{Login, UserInfo};
+use crate::db::UserPermissions;
+
+/// A logged in user. Anonymous browsers are sent to the login page, and API
+/// requests get a 401.
+#[derive(Clone, Debug)]
+pub struct RequireUser(UserInfo);
+
+/// A logged in user that has the capability `C`. Users without it get a 403,
+/// and anonymous requests are handled like with [`RequireUser`].
+#[derive(Clone, Debug)]
+pub struct RequirePermission<C> {
+    info: UserInfo,
+    capability: PhantomData<C>,
+}
+
+/// Answers every rejection of `T` with a bare 404, for pages whose existence
+/// shouldn't be given away.
+#[derive(Clone, Debug)]
+pub struct Hidden<T>(T);
+
+/// A capability that can be required with [`RequirePermission`].
+pub trait RequiredCapability {
+    fn granted(perms: &UserPermissions) -> bool;
+}
+
+#[derive(Clone, Debug)]
+pub struct Admin;
+
+impl RequiredCapability for Admin {
+    fn granted(perms: &UserPermissions) -> bool {
+        perms.is_admin()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Short;
+
+impl RequiredCapability for Short {
+    fn granted(perms: &UserPermissions) -> bool {
+        perms.is_short()
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Invite;
+
+impl RequiredCapability for Invite {
+    fn granted(perms: &UserPermissions) -> bool {
+        perms.can_invite()
+    }
+}
+
+impl Deref for RequireUser {
+    type Target = UserInfo;
+
+    fn deref(&self) -> &UserInfo {
+        &self.0
+    }
+}
+
+impl<C> Deref for RequirePermission<C> {
+    type Target = UserInfo;
+
+    fn deref(&self) -> &UserInfo {
+        &self.info
+    }
+}
+
+impl<T> Deref for Hidden<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl FromRequest for RequireUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let info = req
+            .extensions()
+            .get::<Login>()
+            .and_then(|login| login.info().cloned());
+        let req = req.clone();
+
+        Box::pin(async move {
+            match info {
+                Some(info) => Ok(RequireUser(info)),
+                None => Err(not_logged_in(&req)),
+            }
+        })
+    }
+}
+
+impl<C: RequiredCapability + 'static> FromRequest for RequirePermission<C> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = RequireUser::from_request(req, payload);
+        let req = req.clone();
+
+        Box::pin(async move {
+            let RequireUser(info) = user.await?;
+            if C::granted(&info.perms) {
+                Ok(RequirePermission {
+                    info,
+                    capability: PhantomData,
+                })
+            } else {
+                Err(forbidden(&req).await)
+            }
+        })
+    }
+}
+
+impl<T: FromRequest + 'static> FromRequest for Hidden<T> {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let inner = T::from_request(req, payload);
+
+        Box::pin(async move {
+            match inner.await {
+                Ok(inner) => Ok(Hidden(inner)),
+                Err(_) => Err(rejection(
+                    "not found",
+                    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish(),
+                )),
+            }
+        })
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse {
+    error: &'static str,
+}
+
+/// Whether the request was made by a script rather than by a user clicking around.
+fn is_api_request(req: &HttpRequest) -> bool {
+    fn mentions_json(headers: &HeaderMap, name: header::HeaderName) -> bool {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.contains("application/json"))
+    }
+
+    req.path().starts_with("/api/")
+        || mentions_json(req.headers(), header::ACCEPT)
+        || mentions_json(req.headers(), header::CONTENT_TYPE)
+}
+
+fn rejection(reason: &'static str, response: HttpResponse) -> Error {
+    InternalError::from_response(reason, response).into()
+}
+
+fn not_logged_in(req: &HttpRequest) -> Error {
+    const REASON: &str = "not logged in";
+
+    if is_api_request(req) {
+        return rejection(
+            REASON,
+            HttpResponseBuilder::new(StatusCode::UNAUTHORIZED)
+                .json(ErrorResponse { error: REASON }),
+        );
+    }
+
+    // only pages can be returned to, the target of a form would be requested with the wrong method
+    let location = if req.method() == Method::GET {
+        let next = match req.query_string() {
+            "" => req.path().to_owned(),
+            query => format!("{}?{query}", req.path()),
+        };
+        format!(
+            "/login?{}",
+            serde_urlencoded::to_string([("next", next)]).unwrap()
+        )
+    } else {
+        "/login".to_owned()
+    };
+
+    rejection(
+        REASON,
+        HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", location))
+            .finish(),
+    )
+}
+
+async fn forbidden(req: &HttpRequest) -> Error {
+    const REASON: &str = "missing permission";
+
+    if is_api_request(req) {
+        return rejection(
+            REASON,
+            HttpResponseBuilder::new(StatusCode::FORBIDDEN).json(ErrorResponse { error: REASON }),
+        );
+    }
+
+    let mut response = NamedFile::open_async("res/forbidden.html")
+        .await
+        .unwrap()
+        .into_response(req);
+    *response.status_mut() = StatusCode::FORBIDDEN;
+
+    rejection(REASON, response)
+}
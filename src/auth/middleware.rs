@@ -1,6 +1,9 @@
 use std::cell::RefCell;
+use std::collections::HashMap;
 use std::future::{self, Ready};
 use std::rc::Rc;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
 
 use actix_session::SessionExt;
 use actix_web::dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform};
@@ -9,7 +12,8 @@ use actix_web::{Error, HttpMessage};
 use futures_util::future::LocalBoxFuture;
 use serde::{Deserialize, Serialize};
 
-use crate::db::UserPermissions;
+use crate::audit::RequestOrigin;
+use crate::db::{unix_now, Db, UserPermissions, UserRecord};
 
 // There are two steps in middleware processing.
 // 1. Middleware initialization, middleware factory gets called with
@@ -60,21 +64,58 @@ where
         let service = Rc::clone(&self.service);
 
         Box::pin(async move {
+            // static files don't care who's asking, so they don't need a DB lookup
+            if req.path().starts_with("/static/") || req.path() == "/favicon.ico" {
+                return service.call(req).await;
+            }
+
             let session = req.get_session();
-            let data = req.app_data::<Data<crate::AppData>>().unwrap();
+            let data = req.app_data::<Data<crate::AppData>>().unwrap().clone();
+            let origin = RequestOrigin::of(req.request());
 
             let info = if let Some(logged_in) = session
                 .get::<LoggedInUserSessionData>(crate::session_keys::LOGGED_IN)
                 .unwrap()
             {
-                let name = data.db.get_username(logged_in.id).await;
-                if let Some(name) = name {
-                    let perms = data.db.get_permissions(logged_in.id).await;
+                // sessions from before e.g. a password change are no longer valid
+                let user = data
+                    .user_cache
+                    .get(&data.db, logged_in.id)
+                    .await
+                    .filter(|user| user.session_generation == logged_in.generation);
+
+                // sessions that were revoked by the user are no longer valid either
+                let session_id = match (&user, &logged_in.token) {
+                    (None, _) => None,
+                    (Some(_), Some(token)) => {
+                        data.user_cache
+                            .session(&data.db, logged_in.id, token, &origin)
+                            .await
+                    }
+                    // sessions from before sessions were tracked start being tracked now
+                    (Some(_), None) => {
+                        let (session_id, token) =
+                            data.db.create_session(logged_in.id, &origin).await;
+                        session
+                            .insert(
+                                crate::session_keys::LOGGED_IN,
+                                LoggedInUserSessionData {
+                                    token: Some(token),
+                                    ..logged_in.clone()
+                                },
+                            )
+                            .unwrap();
+                        Some(session_id)
+                    }
+                };
+
+                if let (Some(user), Some(session_id)) = (user, session_id) {
                     session.renew();
                     Some(UserInfo {
                         id: logged_in.id,
-                        name,
-                        perms: perms.unwrap_or_default(),
+                        name: user.name,
+                        perms: user.perms,
+                        session_id,
                     })
                 } else {
                     session.remove(crate::session_keys::LOGGED_IN);
@@ -84,25 +125,68 @@ where
                 None
             };
 
+            // users with 2FA enabled are only half-authenticated until they enter a code
+            let pending = if let Some(pending) = session
+                .get::<PendingLoginSessionData>(crate::session_keys::PENDING_LOGIN)
+                .unwrap()
+            {
+                if info.is_none() && pending.since + PENDING_LOGIN_TIMEOUT > unix_now() {
+                    Some(pending.id)
+                } else {
+                    session.remove(crate::session_keys::PENDING_LOGIN);
+                    None
+                }
+            } else {
+                None
+            };
+
             req.extensions_mut().insert(Login {
                 info,
+                pending,
                 state: Rc::new(RefCell::new(LoginState::Unchanged)),
             });
             let res = service.call(req).await?;
 
             let login = res.request().extensions_mut().remove::<Login>().unwrap();
-            match *login.state.borrow() {
+            let state = *login.state.borrow();
+            match state {
                 LoginState::ToLogin { id } => {
+                    let generation = data.db.get_session_generation(id).await.unwrap_or_default();
+                    let (_, token) = data.db.create_session(id, &origin).await;
                     session
                         .insert(
                             crate::session_keys::LOGGED_IN,
-                            LoggedInUserSessionData { id },
+                            LoggedInUserSessionData {
+                                id,
+                                generation,
+                                token: Some(token),
+                            },
+                        )
+                        .unwrap();
+                    session.remove(crate::session_keys::PENDING_LOGIN);
+                    session.renew();
+                }
+                LoginState::ToPending { id } => {
+                    session
+                        .insert(
+                            crate::session_keys::PENDING_LOGIN,
+                            PendingLoginSessionData {
+                                id,
+                                since: unix_now(),
+                            },
                         )
                         .unwrap();
                     session.renew();
                 }
                 LoginState::ToLogout => {
-                    session.remove(crate::session_keys::LOGGED_IN);
+                    if let Some(Ok(LoggedInUserSessionData {
+                        token: Some(token), ..
+                    })) = session.remove_as(crate::session_keys::LOGGED_IN)
+                    {
+                        data.db.delete_session(&token).await;
+                        data.user_cache.forget_session(&token);
+                    }
+                    session.remove(crate::session_keys::PENDING_LOGIN);
                 }
                 LoginState::Unchanged => {}
             }
@@ -112,14 +196,99 @@ where
     }
 }
 
+/// How long a user's name and permissions are trusted without asking the DB again.
+const USER_CACHE_LIFETIME: Duration = Duration::from_secs(30);
+
+/// Caches what the middleware looks up about logged in users. Anything that changes a
+/// user's name, permissions or session generation, or revokes their sessions, has to
+/// invalidate their entry.
+#[derive(Default, Debug)]
+pub struct UserCache {
+    users: Mutex<HashMap<i64, (Instant, UserRecord)>>,
+    /// The session ids of tokens that weren't revoked, with the users they belong to.
+    sessions: Mutex<HashMap<String, (Instant, i64, i64)>>,
+}
+
+impl UserCache {
+    pub async fn get(&self, db: &Db, id: i64) -> Option<UserRecord> {
+        if let Some((since, user)) = self.users.lock().unwrap().get(&id) {
+            if since.elapsed() < USER_CACHE_LIFETIME {
+                return Some(user.clone());
+            }
+        }
+
+        let user = db.get_user_record(id).await?;
+        let mut users = self.users.lock().unwrap();
+        users.retain(|_, (since, _)| since.elapsed() < USER_CACHE_LIFETIME);
+        users.insert(id, (Instant::now(), user.clone()));
+        Some(user)
+    }
+
+    /// The id of the user's session with the token, unless it was revoked. The DB only
+    /// notes that the session is still in use when it's asked again.
+    pub async fn session(
+        &self,
+        db: &Db,
+        user_id: i64,
+        token: &str,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
+        if let Some(&(since, user, session_id)) = self.sessions.lock().unwrap().get(token) {
+            if user == user_id && since.elapsed() < USER_CACHE_LIFETIME {
+                return Some(session_id);
+            }
+        }
+
+        let session_id = db.touch_session(user_id, token, origin).await?;
+        let mut sessions = self.sessions.lock().unwrap();
+        sessions.retain(|_, (since, _, _)| since.elapsed() < USER_CACHE_LIFETIME);
+        sessions.insert(token.to_owned(), (Instant::now(), user_id, session_id));
+        Some(session_id)
+    }
+
+    pub fn invalidate(&self, id: i64) {
+        self.users.lock().unwrap().remove(&id);
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|_, (_, user, _)| *user != id);
+    }
+
+    /// For a session that ended by logging out.
+    pub fn forget_session(&self, token: &str) {
+        self.sessions.lock().unwrap().remove(token);
+    }
+
+    /// For changes that affect many users at once, like changing a role.
+    pub fn clear(&self) {
+        self.users.lock().unwrap().clear();
+        self.sessions.lock().unwrap().clear();
+    }
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 struct LoggedInUserSessionData {
     id: i64,
+    #[serde(default)]
+    generation: i64,
+    /// Identifies the session in the DB, so that it can be listed and revoked.
+    #[serde(default)]
+    token: Option<String>,
+}
+
+/// How long a user has to enter their 2FA code after entering their password.
+const PENDING_LOGIN_TIMEOUT: i64 = 5 * 60; // seconds
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct PendingLoginSessionData {
+    id: i64,
+    since: i64,
 }
 
 #[derive(Clone, Debug)]
 pub struct Login {
     info: Option<UserInfo>,
+    pending: Option<i64>,
     state: Rc<RefCell<LoginState>>,
 }
 
@@ -128,12 +297,15 @@ pub struct UserInfo {
     pub id: i64,
     pub name: String,
     pub perms: UserPermissions,
+    /// The id of the session the user is logged in with.
+    pub session_id: i64,
 }
 
 #[derive(Copy, Clone, Debug, PartialEq, Eq)]
 enum LoginState {
     Unchanged,
     ToLogin { id: i64 },
+    ToPending { id: i64 },
     ToLogout,
 }
 
@@ -142,6 +314,16 @@ impl Login {
         *self.state.borrow_mut() = LoginState::ToLogin { id };
     }
 
+    /// Marks the user as having passed the first factor (their password) only.
+    pub fn login_pending(&self, id: i64) {
+        *self.state.borrow_mut() = LoginState::ToPending { id };
+    }
+
+    /// The id of the user who has yet to pass the second factor, if there is one.
+    pub fn pending(&self) -> Option<i64> {
+        self.pending
+    }
+
     pub fn logout(&self) -> bool {
         *self.state.borrow_mut() = LoginState::ToLogout;
         self.info.is_some()
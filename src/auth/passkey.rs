@@ -0,0 +1,596 @@
+use actix_session::Session;
+use actix_web::http::header::{self, ContentType};
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data, Json, ReqData};
+use actix_web::{get, post, HttpRequest, HttpResponseBuilder, Responder};
+use argon2::{Algorithm, Argon2, Params, Version};
+use askama::Template;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use webauthn_rs::prelude::{
+    CredentialID, Passkey, PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential,
+    RegisterPublicKeyCredential, RequestChallengeResponse, Uuid,
+};
+
+use crate::audit::{AuditEvent, RequestOrigin};
+use crate::auth::guard::RequireUser;
+use crate::auth::middleware::Login;
+use crate::auth::throttle::ThrottleKey;
+use crate::auth::NextQuery;
+use crate::csrf::CsrfToken;
+use crate::db::format_timestamp;
+
+#[derive(Debug, Clone)]
+pub struct StoredPasskey {
+    pub id: i64,
+    pub credential_id: String,
+    pub name: String,
+    pub passkey: String,
+    pub created_at: i64,
+    pub last_used_at: Option<i64>,
+}
+
+const PASSKEY_NAME_MAX_LENGTH: usize = 64; // bytes
+const DECOY_SALT: &[u8] = b"boolco.dev passkey decoy";
+
+/// Credential ids are stored as they appear in JSON, which is base64url.
+fn encode_credential_id(id: &CredentialID) -> String {
+    match serde_json::to_value(id).unwrap() {
+        serde_json::Value::String(id) => id,
+        _ => unreachable!("credential ids serialize as strings"),
+    }
+}
+
+/// WebAuthn identifies users by an opaque handle, which we derive from their id.
+fn user_handle(id: i64) -> Uuid {
+    Uuid::from_u128(id as u128)
+}
+
+fn load_passkeys(stored: &[StoredPasskey]) -> Vec<Passkey> {
+    stored
+        .iter()
+        // passkeys in the DB are expected to be valid
+        .map(|stored| serde_json::from_str(&stored.passkey).unwrap())
+        .collect()
+}
+
+/// Makes up credentials for usernames that can't log in with a passkey, so that starting a login
+/// doesn't tell who has passkeys, or who exists at all. A username always gets the same one.
+#[derive(Debug)]
+pub struct DecoyCredentials {
+    argon2: Argon2<'static>,
+}
+
+impl DecoyCredentials {
+    pub fn new(secret: &'static [u8]) -> Self {
+        // this only has to be unpredictable without the secret, not slow
+        let params = Params::new(Params::MIN_M_COST, 1, 1, None).unwrap();
+        Self {
+            argon2: Argon2::new_with_secret(secret, Algorithm::Argon2id, Version::V0x13, params)
+                .unwrap(),
+        }
+    }
+
+    /// A made-up credential id for the username, as long as a real one that's encoded
+    /// to `length` characters.
+    fn credential_id(&self, username: &str, length: usize) -> String {
+        let mut id = vec![0; (length * 3 / 4).max(Params::MIN_OUTPUT_LEN)];
+        self.argon2
+            .hash_password_into(username.as_bytes(), DECOY_SALT, &mut id)
+            .unwrap();
+        URL_SAFE_NO_PAD.encode(id)
+    }
+
+    /// Turns a challenge for someone else's passkey into one for the username's
+    /// made-up credential, which looks just the same.
+    fn disguise(&self, challenge: RequestChallengeResponse, username: &str) -> serde_json::Value {
+        let mut challenge = serde_json::to_value(challenge).unwrap();
+        if let Some(serde_json::Value::Array(allowed)) =
+            challenge.pointer_mut("/publicKey/allowCredentials")
+        {
+            for credential in allowed {
+                if let Some(serde_json::Value::String(id)) = credential.get_mut("id") {
+                    *id = self.credential_id(username, id.len());
+                }
+            }
+        }
+        challenge
+    }
+}
+
+struct PasskeyView {
+    id: i64,
+    name: String,
+    created_at: String,
+    last_used_at: String,
+}
+
+#[derive(Template)]
+#[template(path = "account_passkeys.html")]
+struct PasskeysTemplate {
+    passkeys: Vec<PasskeyView>,
+    csrf_token: String,
+}
+
+#[get("/account/passkeys")]
+async fn passkeys_get(
+    data: Data<crate::AppData>,
+    info: RequireUser,
+    csrf: CsrfToken,
+) -> impl Responder {
+    if data.webauthn.is_some() {
+        let passkeys = data
+            .db
+            .get_passkeys(info.id)
+            .await
+            .into_iter()
+            .map(|stored| PasskeyView {
+                id: stored.id,
+                name: stored.name,
+                created_at: format_timestamp(stored.created_at),
+                last_used_at: stored
+                    .last_used_at
+                    .map(format_timestamp)
+                    .unwrap_or_else(|| "never".into()),
+            })
+            .collect();
+
+        return HttpResponseBuilder::new(StatusCode::OK)
+            .content_type(ContentType::html())
+            .body(
+                PasskeysTemplate {
+                    passkeys,
+                    csrf_token: csrf.get().to_owned(),
+                }
+                .to_string(),
+            );
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+#[post("/account/passkeys/register/start")]
+async fn register_start(
+    data: Data<crate::AppData>,
+    session: Session,
+    info: RequireUser,
+) -> impl Responder {
+    let Some(webauthn) = &data.webauthn else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    let existing = load_passkeys(&data.db.get_passkeys(info.id).await)
+        .iter()
+        .map(|passkey| passkey.cred_id().clone())
+        .collect();
+
+    let Ok((challenge, state)) = webauthn.start_passkey_registration(
+        user_handle(info.id),
+        &info.name,
+        &info.name,
+        Some(existing),
+    ) else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    };
+
+    session
+        .insert(crate::session_keys::PASSKEY_REGISTRATION, state)
+        .unwrap();
+
+    HttpResponseBuilder::new(StatusCode::OK).json(challenge)
+}
+
+#[derive(Deserialize)]
+struct RegisterFinishRequest {
+    name: String,
+    credential: RegisterPublicKeyCredential,
+}
+
+#[derive(Serialize)]
+struct RedirectResponse<'a> {
+    redirect: &'a str,
+}
+
+#[post("/account/passkeys/register/finish")]
+async fn register_finish(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    body: Json<RegisterFinishRequest>,
+    session: Session,
+    info: RequireUser,
+) -> impl Responder {
+    let Some(webauthn) = &data.webauthn else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    // a registration challenge can only be answered once
+    let Some(Ok(state)) =
+        session.remove_as::<PasskeyRegistration>(crate::session_keys::PASSKEY_REGISTRATION)
+    else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    };
+
+    let name = body.name.trim();
+    let name = if name.is_empty() { "passkey" } else { name };
+    if name.len() > PASSKEY_NAME_MAX_LENGTH {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    let Ok(passkey) = webauthn.finish_passkey_registration(&body.credential, &state) else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    };
+
+    if data
+        .db
+        .add_passkey(
+            info.id,
+            &encode_credential_id(passkey.cred_id()),
+            name,
+            &serde_json::to_string(&passkey).unwrap(),
+        )
+        .await
+    {
+        data.db
+            .audit(
+                AuditEvent::PasskeyRegistered,
+                Some(info.id),
+                &format!("{}: {}", info.name, name),
+                &RequestOrigin::of(&req),
+            )
+            .await;
+        HttpResponseBuilder::new(StatusCode::OK).json(RedirectResponse {
+            redirect: "/account/passkeys",
+        })
+    } else {
+        HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish()
+    }
+}
+
+#[derive(Deserialize)]
+struct DeletePasskeyForm {
+    id: i64,
+}
+
+#[post("/account/passkeys/delete")]
+async fn delete(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    form: web::Form<DeletePasskeyForm>,
+    info: RequireUser,
+) -> impl Responder {
+    if data.db.delete_passkey(info.id, form.id).await {
+        data.db
+            .audit(
+                AuditEvent::PasskeyDeleted,
+                Some(info.id),
+                &format!("{}: #{}", info.name, form.id),
+                &RequestOrigin::of(&req),
+            )
+            .await;
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/account/passkeys"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+#[derive(Serialize, Deserialize)]
+struct PendingPasskeyLogin {
+    id: i64,
+    state: PasskeyAuthentication,
+}
+
+#[derive(Deserialize)]
+struct LoginStartRequest {
+    username: String,
+}
+
+#[post("/login/passkey/start")]
+async fn login_start(
+    data: Data<crate::AppData>,
+    body: Json<LoginStartRequest>,
+    session: Session,
+    login: ReqData<Login>,
+) -> impl Responder {
+    let Some(webauthn) = &data.webauthn else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    if login.info().is_some() || !crate::auth::verify_username(&body.username) {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    }
+
+    let user = data.db.get_user_id(&body.username).await;
+    let mut passkeys = vec![];
+    if let Some(id) = user {
+        if !data.db.is_disabled(id).await {
+            passkeys = load_passkeys(&data.db.get_passkeys(id).await);
+        }
+    }
+
+    // everyone gets a challenge, whether they can answer it or not
+    let (challenge, pending) = match user {
+        Some(id) if !passkeys.is_empty() => {
+            let Ok((challenge, state)) = webauthn.start_passkey_authentication(&passkeys) else {
+                return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+            };
+            (
+                serde_json::to_value(challenge).unwrap(),
+                Some(PendingPasskeyLogin { id, state }),
+            )
+        }
+        _ => {
+            // a decoy is modelled on a real passkey, so that the two can't be told apart
+            let Some(model) = data.db.get_latest_passkey().await else {
+                // nobody has a passkey, so there's nobody to tell apart either
+                return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+            };
+            let model: Passkey = serde_json::from_str(&model.passkey).unwrap();
+            let Ok((challenge, _)) = webauthn.start_passkey_authentication(&[model]) else {
+                return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+            };
+            (
+                data.passkey_decoys.disguise(challenge, &body.username),
+                None,
+            )
+        }
+    };
+
+    session
+        .insert(crate::session_keys::PASSKEY_AUTHENTICATION, pending)
+        .unwrap();
+
+    HttpResponseBuilder::new(StatusCode::OK).json(challenge)
+}
+
+#[post("/login/passkey/finish")]
+async fn login_finish(
+    req: HttpRequest,
+    data: Data<crate::AppData>,
+    body: Json<PublicKeyCredential>,
+    query: web::Query<NextQuery>,
+    session: Session,
+    login: ReqData<Login>,
+) -> impl Responder {
+    let Some(webauthn) = &data.webauthn else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    let throttle = &data.state.login_throttle;
+    let keys: Vec<_> = ThrottleKey::ip(&req).into_iter().collect();
+    if let Some(retry_after) = throttle.check(&keys) {
+        return HttpResponseBuilder::new(StatusCode::TOO_MANY_REQUESTS)
+            .insert_header((header::RETRY_AFTER, retry_after))
+            .finish();
+    }
+
+    // an authentication challenge can only be answered once
+    let Some(Ok(pending)) = session
+        .remove_as::<Option<PendingPasskeyLogin>>(crate::session_keys::PASSKEY_AUTHENTICATION)
+    else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST).finish();
+    };
+    // decoys can't be answered, which looks just like a wrong passkey
+    let Some(PendingPasskeyLogin { id, state }) = pending else {
+        throttle.fail(&keys);
+        return HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish();
+    };
+
+    let name = data.db.get_username(id).await.unwrap_or_default();
+    let Ok(result) = webauthn.finish_passkey_authentication(&body, &state) else {
+        throttle.fail(&keys);
+        data.db
+            .audit(
+                AuditEvent::LoginFailed,
+                Some(id),
+                &name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+        return HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish();
+    };
+
+    let credential_id = encode_credential_id(result.cred_id());
+    let stored = data.db.get_passkeys(id).await;
+    let Some(stored) = stored
+        .iter()
+        .find(|stored| stored.credential_id == credential_id)
+    else {
+        // the passkey was deleted in the meantime
+        return HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish();
+    };
+
+    // keep the signature counter up to date, so that cloned authenticators can be detected
+    let mut passkey: Passkey = serde_json::from_str(&stored.passkey).unwrap();
+    passkey.update_credential(&result);
+    data.db
+        .update_passkey(
+            id,
+            &credential_id,
+            &serde_json::to_string(&passkey).unwrap(),
+        )
+        .await;
+
+    // the user may have been disabled since the challenge was issued
+    if data.db.is_disabled(id).await {
+        data.db
+            .audit(
+                AuditEvent::LoginFailed,
+                Some(id),
+                &name,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+        return HttpResponseBuilder::new(StatusCode::FORBIDDEN).finish();
+    }
+
+    data.db
+        .audit(AuditEvent::Login, Some(id), &name, &RequestOrigin::of(&req))
+        .await;
+
+    // a passkey is already a strong factor on its own, so this skips TOTP
+    session
+        .insert(crate::session_keys::SUCCESSFUL, "logged in")
+        .unwrap();
+    login.login(id);
+
+    HttpResponseBuilder::new(StatusCode::OK).json(RedirectResponse {
+        redirect: crate::auth::safe_next(&query.next).unwrap_or("/"),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::http::StatusCode;
+    use actix_web::test::{self, TestRequest};
+    use actix_web::App;
+    use webauthn_authenticator_rs::softpasskey::SoftPasskey;
+    use webauthn_authenticator_rs::WebauthnAuthenticator;
+    use webauthn_rs::prelude::{
+        CreationChallengeResponse, PublicKeyCredential, RequestChallengeResponse, Url,
+        WebauthnBuilder,
+    };
+
+    use crate::csrf::HEADER_NAME;
+    use crate::testing::{self, Browser};
+
+    fn allowed_credentials(challenge: &serde_json::Value) -> Vec<String> {
+        challenge["publicKey"]["allowCredentials"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|credential| credential["id"].as_str().unwrap().to_owned())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn passkey_login_does_not_tell_who_has_passkeys() {
+        let origin = Url::parse("https://localhost").unwrap();
+        let webauthn = WebauthnBuilder::new("localhost", &origin)
+            .unwrap()
+            .build()
+            .unwrap();
+        let data = testing::app_data(Some(webauthn), None).await;
+        testing::create_user(&data, "alice", "correct horse battery").await;
+        testing::create_user(&data, "bob", "staple paper clip").await;
+        let app = test::init_service(
+            App::new()
+                .app_data(data.clone())
+                .wrap(crate::csrf::Csrf)
+                .wrap(crate::auth::middleware::Auth)
+                .wrap(testing::sessions(&data))
+                .service(crate::auth::login_get)
+                .service(crate::auth::login_post)
+                .service(super::register_start)
+                .service(super::register_finish)
+                .service(super::login_start)
+                .service(super::login_finish),
+        )
+        .await;
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+
+        // alice logs in with her password, and registers a passkey
+        let mut browser = Browser::default();
+        let req = browser.request(TestRequest::get().uri("/login"));
+        let res = test::call_service(&app, req.to_request()).await;
+        browser.update(&res);
+        let csrf_token = testing::csrf_token(&test::read_body(res).await);
+
+        let req = browser.request(TestRequest::post().uri("/login").set_form([
+            ("username", "alice"),
+            ("password", "correct horse battery"),
+            ("csrf_token", &csrf_token),
+        ]));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert!(res.status().is_redirection());
+        browser.update(&res);
+
+        let req = browser.request(
+            TestRequest::post()
+                .uri("/account/passkeys/register/start")
+                .insert_header((HEADER_NAME, csrf_token.as_str())),
+        );
+        let res = test::call_service(&app, req.to_request()).await;
+        assert!(res.status().is_success());
+        browser.update(&res);
+        let challenge: CreationChallengeResponse = test::read_body_json(res).await;
+        let credential = authenticator
+            .do_registration(origin.clone(), challenge)
+            .unwrap();
+
+        let req = browser.request(
+            TestRequest::post()
+                .uri("/account/passkeys/register/finish")
+                .insert_header((HEADER_NAME, csrf_token.as_str()))
+                .set_json(serde_json::json!({ "name": "laptop", "credential": credential })),
+        );
+        let res = test::call_service(&app, req.to_request()).await;
+        assert!(res.status().is_success());
+
+        // then logs in with it somewhere else
+        let mut browser = Browser::default();
+        let req = browser.request(TestRequest::get().uri("/login"));
+        let res = test::call_service(&app, req.to_request()).await;
+        browser.update(&res);
+        let csrf_token = testing::csrf_token(&test::read_body(res).await);
+        let start = |username: &str| {
+            browser
+                .request(
+                    TestRequest::post()
+                        .uri("/login/passkey/start")
+                        .insert_header((HEADER_NAME, csrf_token.as_str()))
+                        .set_json(serde_json::json!({ "username": username })),
+                )
+                .to_request()
+        };
+
+        let res = test::call_service(&app, start("alice")).await;
+        assert!(res.status().is_success());
+        let challenge: serde_json::Value = test::read_body_json(res).await;
+        let real = allowed_credentials(&challenge);
+        assert_eq!(real.len(), 1);
+        let challenge: RequestChallengeResponse = serde_json::from_value(challenge).unwrap();
+        let credential = authenticator
+            .do_authentication(origin.clone(), challenge)
+            .unwrap();
+
+        // bob has no passkey and carol doesn't exist, but neither can be told apart from alice
+        let mut decoys = vec![];
+        for username in ["bob", "carol", "carol"] {
+            let res = test::call_service(&app, start(username)).await;
+            assert!(res.status().is_success());
+            let challenge: serde_json::Value = test::read_body_json(res).await;
+            let allowed = allowed_credentials(&challenge);
+            assert_eq!(allowed.len(), 1);
+            assert_eq!(allowed[0].len(), real[0].len());
+            assert_ne!(allowed, real);
+            decoys.push(allowed);
+        }
+        assert_ne!(decoys[0], decoys[1]);
+        // asking again mustn't give away that the credential is made up
+        assert_eq!(decoys[1], decoys[2]);
+
+        // a decoy challenge can't be answered, even with a real passkey
+        let finish = |credential: &PublicKeyCredential| {
+            browser
+                .request(
+                    TestRequest::post()
+                        .uri("/login/passkey/finish")
+                        .insert_header((HEADER_NAME, csrf_token.as_str()))
+                        .set_json(credential),
+                )
+                .to_request()
+        };
+        let res = test::call_service(&app, finish(&credential)).await;
+        assert_eq!(res.status(), StatusCode::FORBIDDEN);
+
+        let res = test::call_service(&app, start("alice")).await;
+        assert!(res.status().is_success());
+        let challenge: RequestChallengeResponse = test::read_body_json(res).await;
+        let credential = authenticator.do_authentication(origin, challenge).unwrap();
+        let res = test::call_service(&app, finish(&credential)).await;
+        assert!(res.status().is_success());
+    }
+}
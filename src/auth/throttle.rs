@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::sync::Mutex;
+
+use actix_web::HttpRequest;
+
+use crate::db::unix_now;
+
+/// How many failures are allowed before lockouts start.
+const FREE_ATTEMPTS: u32 = 5;
+/// The first lockout's length, which doubles with every further failure.
+const BASE_LOCKOUT: i64 = 30; // seconds
+const MAX_LOCKOUT: i64 = 60 * 60; // seconds
+/// Failures are forgotten after this long without a new one.
+const FORGET_AFTER: i64 = 24 * 60 * 60; // seconds
+/// How many keys are tracked at most, so that failures with made-up usernames can't use up
+/// all memory. Once there are more, the keys that failed the longest time ago are forgotten.
+const MAX_KEYS: usize = 100_000;
+
+/// Something that failed attempts are counted against.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ThrottleKey {
+    Ip(IpAddr),
+    /// Passwords tried for a username from one address. Keying this on the username alone
+    /// would let anyone keep any account locked out.
+    Username(IpAddr, String),
+    /// Second factor codes entered for the user with this id. This is separate from their
+    /// username, so that logging in with the password again doesn't clear it.
+    SecondFactor(i64),
+}
+
+impl ThrottleKey {
+    /// The key for the request's peer, if it has one.
+    pub fn ip(req: &HttpRequest) -> Option<Self> {
+        req.peer_addr().map(|addr| Self::Ip(addr.ip()))
+    }
+
+    /// The key for attempts at the username from the request's peer, if it has one.
+    pub fn username(req: &HttpRequest, name: &str) -> Option<Self> {
+        req.peer_addr()
+            .map(|addr| Self::Username(addr.ip(), name.to_owned()))
+    }
+}
+
+impl Display for ThrottleKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ip(ip) => write!(f, "ip:{ip}"),
+            Self::Username(ip, name) => write!(f, "user:{ip}/{name}"),
+            Self::SecondFactor(id) => write!(f, "2fa:{id}"),
+        }
+    }
+}
+
+impl FromStr for ThrottleKey {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(ip) = s.strip_prefix("ip:") {
+            ip.parse().map(Self::Ip).map_err(|_| ())
+        } else if let Some(key) = s.strip_prefix("user:") {
+            // addresses never contain a slash, so the first one ends it
+            let (ip, name) = key.split_once('/').ok_or(())?;
+            Ok(Self::Username(ip.parse().map_err(|_| ())?, name.into()))
+        } else if let Some(id) = s.strip_prefix("2fa:") {
+            id.parse().map(Self::SecondFactor).map_err(|_| ())
+        } else {
+            Err(())
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Attempts {
+    failures: u32,
+    last_failure: i64,
+    locked_until: i64,
+}
+
+#[derive(Clone, Debug)]
+pub struct Lockout {
+    pub key: ThrottleKey,
+    pub failures: u32,
+    pub locked_until: i64,
+}
+
+/// Tracks failed authentication attempts in memory, locking keys out with exponential backoff.
+#[derive(Default, Debug)]
+pub struct Throttle {
+    attempts: Mutex<HashMap<ThrottleKey, Attempts>>,
+}
+
+impl Throttle {
+    /// Returns the number of seconds until another attempt is allowed, if any of the keys is locked out.
+    pub fn check(&self, keys: &[ThrottleKey]) -> Option<i64> {
+        let now = unix_now();
+        let attempts = self.attempts.lock().unwrap();
+
+        keys.iter()
+            .filter_map(|key| attempts.get(key))
+            .map(|attempts| attempts.locked_until - now)
+            .filter(|&retry_after| retry_after > 0)
+            .max()
+    }
+
+    pub fn fail(&self, keys: &[ThrottleKey]) {
+        let now = unix_now();
+        let mut attempts = self.attempts.lock().unwrap();
+        attempts.retain(|_, attempts| attempts.last_failure + FORGET_AFTER > now);
+
+        let new_keys = keys
+            .iter()
+            .filter(|key| !attempts.contains_key(key))
+            .count();
+        if attempts.len() + new_keys > MAX_KEYS {
+            // make some room at once, so that this doesn't happen on every failure
+            let mut oldest: Vec<_> = attempts
+                .iter()
+                .map(|(key, attempts)| (attempts.last_failure, key.clone()))
+                .collect();
+            oldest.sort_unstable();
+            let excess = attempts.len() + new_keys - MAX_KEYS * 9 / 10;
+            for (_, key) in oldest.into_iter().take(excess) {
+                attempts.remove(&key);
+            }
+        }
+
+        for key in keys {
+            let attempts = attempts.entry(key.clone()).or_insert(Attempts {
+                failures: 0,
+                last_failure: now,
+                locked_until: 0,
+            });
+            attempts.failures += 1;
+            attempts.last_failure = now;
+
+            if let Some(excess) = attempts.failures.checked_sub(FREE_ATTEMPTS) {
+                let lockout = BASE_LOCKOUT
+                    .saturating_mul(2i64.saturating_pow(excess))
+                    .min(MAX_LOCKOUT);
+                attempts.locked_until = now + lockout;
+            }
+        }
+    }
+
+    /// Forgets all failures of the given key. Returns whether there were any.
+    pub fn clear(&self, key: &ThrottleKey) -> bool {
+        self.attempts.lock().unwrap().remove(key).is_some()
+    }
+
+    /// All keys with recent failures, most recently locked first.
+    pub fn lockouts(&self) -> Vec<Lockout> {
+        let now = unix_now();
+        let mut lockouts: Vec<_> = self
+            .attempts
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(_, attempts)| attempts.last_failure + FORGET_AFTER > now)
+            .map(|(key, attempts)| Lockout {
+                key: key.clone(),
+                failures: attempts.failures,
+                locked_until: attempts.locked_until,
+            })
+            .collect();
+
+        lockouts.sort_by(|a, b| {
+            b.locked_until
+                .cmp(&a.locked_until)
+                .then_with(|| a.key.cmp(&b.key))
+        });
+        lockouts
+    }
+}
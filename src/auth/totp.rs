@@ -0,0 +1,73 @@
+use argon2::password_hash::rand_core::{OsRng, RngCore};
+use rand::seq::SliceRandom;
+use totp_rs::{Algorithm, Secret, TOTP};
+
+const ISSUER: &str = "boolco.dev";
+const DIGITS: usize = 6;
+const STEP: i64 = 30; // seconds
+/// How many steps before and after the current one are still accepted, to account for clock drift.
+const SKEW: i64 = 1;
+
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_ALPHABET: &[u8] = b"abcdefghjkmnpqrstuvwxyz23456789";
+
+fn totp(secret: &str, account: &str) -> Option<TOTP> {
+    let secret = Secret::Encoded(secret.to_owned()).to_bytes().ok()?;
+
+    // skew is handled by `check`, which also needs to know the step that matched
+    TOTP::new(
+        Algorithm::SHA1,
+        DIGITS,
+        0,
+        STEP as u64,
+        secret,
+        Some(ISSUER.into()),
+        account.into(),
+    )
+    .ok()
+}
+
+/// Generates a new base32-encoded secret.
+pub fn generate_secret() -> String {
+    let mut bytes = [0u8; 20];
+    OsRng.fill_bytes(&mut bytes);
+
+    match Secret::Raw(bytes.to_vec()).to_encoded() {
+        Secret::Encoded(secret) => secret,
+        Secret::Raw(_) => unreachable!(),
+    }
+}
+
+/// The QR code an authenticator app can scan to enroll `secret`, as a base64-encoded PNG.
+pub fn qr_code(secret: &str, account: &str) -> Option<String> {
+    totp(secret, account)?.get_qr_base64().ok()
+}
+
+/// Returns the time step in which `code` is valid, if it's valid at all.
+pub fn check(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let totp = totp(secret, "")?;
+    let current = now / STEP;
+
+    (current - SKEW..=current + SKEW).find(|step| totp.check(code, (step * STEP) as u64))
+}
+
+pub fn generate_recovery_codes() -> Vec<String> {
+    let mut rng = rand::thread_rng();
+
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = (0..10)
+                .map(|_| *RECOVERY_CODE_ALPHABET.choose(&mut rng).unwrap() as char)
+                .collect();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are compared without the dash and case-insensitively.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(char::is_ascii_alphanumeric)
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
@@ -0,0 +1,210 @@
+use std::future::{self, Ready};
+use std::rc::Rc;
+
+use actix_files::NamedFile;
+use actix_session::{Session, SessionExt};
+use actix_web::body::EitherBody;
+use actix_web::dev::{forward_ready, Payload, Service, ServiceRequest, ServiceResponse, Transform};
+use actix_web::http::{header, Method, StatusCode};
+use actix_web::web::BytesMut;
+use actix_web::{Error, FromRequest, HttpRequest, HttpResponse};
+use futures_util::future::LocalBoxFuture;
+use futures_util::StreamExt;
+use rand::distributions::{Alphanumeric, DistString};
+
+/// The name of the hidden form field the token is submitted with.
+pub const FIELD_NAME: &str = "csrf_token";
+/// The header scripts submit the token with.
+pub const HEADER_NAME: &str = "X-CSRF-Token";
+
+const TOKEN_LENGTH: usize = 32;
+/// Form bodies larger than this aren't searched for a token, and so are rejected.
+const FORM_LIMIT: usize = 16 * 1024; // bytes, same as actix's default form limit
+
+/// Paths whose handlers read multipart bodies, and check the token in them themselves.
+const MULTIPART_PATHS: &[&str] = &["/short/import"];
+
+/// The session's CSRF token, which every state-changing request has to carry.
+///
+/// Handlers that render a form extract it and pass it on to their templates. The token is only
+/// created then, so that visitors who never see a form don't get a session.
+#[derive(Clone, Debug)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    /// The session's token, which is created if the session doesn't have one yet.
+    pub fn for_session(session: &Session) -> Self {
+        if let Some(token) = session
+            .get::<String>(crate::session_keys::CSRF_TOKEN)
+            .unwrap()
+        {
+            return Self(token);
+        }
+
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), TOKEN_LENGTH);
+        session
+            .insert(crate::session_keys::CSRF_TOKEN, &token)
+            .unwrap();
+        Self(token)
+    }
+
+    pub fn get(&self) -> &str {
+        &self.0
+    }
+
+    /// Whether a token submitted by the client is this one.
+    pub fn matches(&self, submitted: &str) -> bool {
+        tokens_match(submitted, &self.0)
+    }
+}
+
+impl FromRequest for CsrfToken {
+    type Error = Error;
+    type Future = Ready<Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        future::ready(Ok(Self::for_session(&req.get_session())))
+    }
+}
+
+/// The response to a request that didn't carry the right token.
+pub async fn rejected(req: &HttpRequest) -> HttpResponse {
+    let mut res = NamedFile::open_async("res/csrf_failed.html")
+        .await
+        .unwrap()
+        .into_response(req);
+    *res.status_mut() = StatusCode::FORBIDDEN;
+    res
+}
+
+/// Rejects POST requests that don't carry the session's CSRF token, either in the
+/// `X-CSRF-Token` header or the `csrf_token` field of a urlencoded form.
+/// Sessions without a token have never been shown a form, so their requests are rejected too.
+pub struct Csrf;
+
+impl<S: 'static, B> Transform<S, ServiceRequest> for Csrf
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error>,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type InitError = ();
+    type Transform = CsrfMiddleware<S>;
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        future::ready(Ok(CsrfMiddleware {
+            service: Rc::new(service),
+        }))
+    }
+}
+
+pub struct CsrfMiddleware<S> {
+    service: Rc<S>,
+}
+
+impl<S, B> Service<ServiceRequest> for CsrfMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    S::Future: 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, mut req: ServiceRequest) -> Self::Future {
+        let service = Rc::clone(&self.service);
+
+        Box::pin(async move {
+            if needs_token(&req) {
+                let token = req
+                    .get_session()
+                    .get::<String>(crate::session_keys::CSRF_TOKEN)
+                    .unwrap();
+                let submitted = submitted_token(&mut req).await?;
+
+                let valid = token
+                    .zip(submitted)
+                    .is_some_and(|(token, submitted)| tokens_match(&submitted, &token));
+                if !valid {
+                    let res = rejected(req.request()).await;
+                    return Ok(req.into_response(res).map_into_right_body());
+                }
+            }
+
+            let res = service.call(req).await?;
+
+            Ok(res.map_into_left_body())
+        })
+    }
+}
+
+fn needs_token(req: &ServiceRequest) -> bool {
+    if req.method() != Method::POST {
+        return false;
+    }
+
+    if MULTIPART_PATHS.contains(&req.path()) {
+        return false;
+    }
+
+    // tickets are handed out to loopback clients like curl, which never see a form
+    let loopback = req.peer_addr().is_some_and(|addr| addr.ip().is_loopback());
+    !(loopback && req.path().starts_with("/register/"))
+}
+
+/// Finds the token submitted with the request. Urlencoded bodies are read in full,
+/// and put back so that the handler can still extract the form.
+async fn submitted_token(req: &mut ServiceRequest) -> Result<Option<String>, Error> {
+    if let Some(value) = req.headers().get(HEADER_NAME) {
+        return Ok(value.to_str().ok().map(str::to_owned));
+    }
+
+    let is_form = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/x-www-form-urlencoded"));
+    if !is_form {
+        return Ok(None);
+    }
+
+    let mut payload = req.take_payload();
+    let mut body = BytesMut::new();
+    while let Some(chunk) = payload.next().await {
+        let chunk = chunk?;
+        if body.len() + chunk.len() > FORM_LIMIT {
+            return Ok(None);
+        }
+        body.extend_from_slice(&chunk);
+    }
+
+    let body = body.freeze();
+    let token = find_field(&body);
+    req.set_payload(Payload::from(body));
+
+    Ok(token)
+}
+
+fn find_field(urlencoded: &[u8]) -> Option<String> {
+    serde_urlencoded::from_bytes::<Vec<(String, String)>>(urlencoded)
+        .ok()?
+        .into_iter()
+        .find(|(key, _)| key == FIELD_NAME)
+        .map(|(_, value)| value)
+}
+
+/// Compares the tokens in constant time, so that they can't be guessed byte by byte.
+fn tokens_match(submitted: &str, token: &str) -> bool {
+    submitted.len() == token.len()
+        && submitted
+            .bytes()
+            .zip(token.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
@@ -1,62 +1,271 @@
+use std::collections::HashMap;
 use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use argon2::password_hash::rand_core::{OsRng, RngCore};
 use argon2::password_hash::{Encoding, SaltString};
 use argon2::{Argon2, PasswordHash, PasswordHasher, PasswordVerifier};
 use base64::Engine;
+use log::warn;
 use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
 use sqlx::sqlite::SqliteConnectOptions;
-use sqlx::{query, query_as, QueryBuilder, SqlitePool};
+use sqlx::{query, query_as, query_scalar, QueryBuilder, SqlitePool};
 
+#[cfg(not(feature = "prepare_db"))]
+use crate::audit::{AuditEvent, AuditFilter, AuditRecord, RequestOrigin};
 #[cfg(not(feature = "prepare_db"))]
 use crate::game::GameMessage;
 
 const HASH_ENCODING: Encoding = Encoding::B64;
 const TICKET_ENGINE: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
 
-#[derive(Clone, Copy, Debug, Default)]
+/// Something a user may be allowed to do. Users get capabilities through their roles,
+/// which are stored in the `roles`, `role_capabilities` and `user_roles` tables.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Capability {
+    Admin,
+    Short,
+    Invite,
+}
+
+impl Capability {
+    pub const ALL: [Self; 3] = [Self::Admin, Self::Short, Self::Invite];
+
+    /// The capability's name, as stored in the DB.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Admin => "admin",
+            Self::Short => "short",
+            Self::Invite => "invite",
+        }
+    }
+
+    fn bit(&self) -> u64 {
+        1u64 << *self as u8
+    }
+}
+
+impl FromStr for Capability {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::ALL.into_iter().find(|x| x.as_str() == s).ok_or(())
+    }
+}
+
+/// A set of capabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
 pub struct UserPermissions {
     inner: u64,
 }
 
 impl UserPermissions {
-    const ADMIN: u8 = 0;
-    const SHORT: u8 = 1;
-
-    fn admin(&mut self, set: bool) {
+    pub fn set(&mut self, capability: Capability, set: bool) {
         if set {
-            self.inner |= 1u64 << Self::ADMIN;
+            self.inner |= capability.bit();
         } else {
-            self.inner &= !(1u64 << Self::ADMIN);
+            self.inner &= !capability.bit();
         }
     }
 
+    pub fn has(&self, capability: Capability) -> bool {
+        self.inner & capability.bit() != 0
+    }
+
     pub fn is_admin(&self) -> bool {
-        self.inner & (1u64 << Self::ADMIN) != 0
+        self.has(Capability::Admin)
     }
 
-    fn short(&mut self, set: bool) {
-        if set {
-            self.inner |= 1u64 << Self::SHORT;
-        } else {
-            self.inner &= !(1u64 << Self::SHORT);
+    pub fn is_short(&self) -> bool {
+        self.is_admin() || self.has(Capability::Short)
+    }
+
+    pub fn can_invite(&self) -> bool {
+        self.is_admin() || self.has(Capability::Invite)
+    }
+
+    pub fn capabilities(&self) -> impl Iterator<Item = Capability> + '_ {
+        Capability::ALL.into_iter().filter(|x| self.has(*x))
+    }
+}
+
+impl FromIterator<Capability> for UserPermissions {
+    fn from_iter<T: IntoIterator<Item = Capability>>(iter: T) -> Self {
+        let mut perms = Self::default();
+        for capability in iter {
+            perms.set(capability, true);
         }
+        perms
     }
+}
 
-    pub fn is_short(&self) -> bool {
-        self.is_admin() || self.inner & (1u64 << Self::SHORT) != 0
+#[derive(Clone, Debug)]
+pub struct Role {
+    pub id: i64,
+    pub name: String,
+    pub capabilities: UserPermissions,
+}
+
+/// What the auth middleware needs to know about a logged in user on every request.
+#[derive(Clone, Debug)]
+pub struct UserRecord {
+    pub name: String,
+    pub session_generation: i64,
+    pub perms: UserPermissions,
+}
+
+/// A user's identity at an external OpenID Connect provider.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct OidcIdentity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// The role that can't be changed or deleted, so that admins can't lock themselves out.
+pub const ADMIN_ROLE: &str = "admin";
+/// The role that ticket holders get when their ticket grants link shortening. It can't be changed
+/// or deleted either, since tickets grant it by name.
+pub const SHORT_ROLE: &str = "short";
+
+/// Limits on how many short links a user may have. The defaults come from `config.toml`,
+/// and can be overridden per user in the `short_link_quotas` table.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct ShortQuota {
+    pub max_links: i64,
+    pub max_links_per_day: i64,
+}
+
+impl Default for ShortQuota {
+    fn default() -> Self {
+        Self {
+            max_links: 1000,
+            max_links_per_day: 100,
+        }
+    }
+}
+
+/// How many people a user with the invite capability may invite. Both outstanding
+/// invite tickets and users who already registered through one count towards it.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct InviteQuota {
+    pub max_invites: i64,
+}
+
+impl Default for InviteQuota {
+    fn default() -> Self {
+        Self { max_invites: 5 }
+    }
+}
+
+/// A user together with whoever invited them, for drawing the invite tree.
+#[derive(Clone, Debug)]
+pub struct InvitedUser {
+    pub id: i64,
+    pub name: String,
+    pub invited_by: Option<i64>,
+    pub disabled: bool,
+}
+
+/// The cost of password hashing. Raising these makes existing hashes get rehashed
+/// with the new parameters the next time their user logs in.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct Argon2Config {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for Argon2Config {
+    fn default() -> Self {
+        Self {
+            memory_kib: argon2::Params::DEFAULT_M_COST,
+            iterations: argon2::Params::DEFAULT_T_COST,
+            parallelism: argon2::Params::DEFAULT_P_COST,
+        }
     }
 }
 
+/// The secrets mixed into password hashes, by key id. New hashes are made with the
+/// current one, and old ones are rehashed with it when their user logs in.
+#[derive(Clone, Debug)]
+pub struct Peppers {
+    pub keys: HashMap<i64, &'static [u8]>,
+    pub current: i64,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ShortUsage {
+    pub links: i64,
+    pub links_today: i64,
+    pub quota: ShortQuota,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InviteError {
+    /// The name is already taken by a user or another ticket.
+    NameTaken,
+    NoInvitesLeft,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ShortLinkError {
+    NoSuchUser,
+    Taken,
+    TooManyLinks(i64),
+    TooManyToday(i64),
+}
+
+const DAY: i64 = 24 * 60 * 60; // seconds
+
+/// How long a session lives in the session store without being used.
+pub const SESSION_LIFETIME: i64 = 7 * DAY;
+const SESSION_TOKEN_LENGTH: usize = 32;
+/// How often a session's last-seen time is updated.
+const SESSION_TOUCH_INTERVAL: i64 = 60; // seconds
+
+pub fn unix_now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}
+
+/// Formats a unix timestamp, as stored in the DB, for display.
+pub fn format_timestamp(timestamp: i64) -> String {
+    use time::macros::format_description;
+
+    time::OffsetDateTime::from_unix_timestamp(timestamp)
+        .ok()
+        .and_then(|x| {
+            x.format(format_description!(
+                "[year]-[month]-[day] [hour]:[minute] UTC"
+            ))
+            .ok()
+        })
+        .unwrap_or_else(|| "-".into())
+}
+
 #[derive(Debug)]
 pub struct Db {
     pool: SqlitePool,
-    argon2: Argon2<'static>,
+    /// One hasher per pepper, by the pepper's key id.
+    argon2: HashMap<i64, Argon2<'static>>,
+    current_pepper: i64,
+    short_quota: ShortQuota,
+    invite_quota: InviteQuota,
 }
 
 #[cfg(not(feature = "prepare_db"))]
 impl Db {
-    pub async fn new(filename: &str, pepper: &'static [u8]) -> Self {
+    pub async fn new(
+        filename: &str,
+        peppers: Peppers,
+        argon2_config: Argon2Config,
+        short_quota: ShortQuota,
+        invite_quota: InviteQuota,
+    ) -> Self {
         let options = SqliteConnectOptions::new()
             .filename(filename)
             .create_if_missing(true)
@@ -64,6 +273,7 @@ impl Db {
         let pool = SqlitePool::connect_with(options).await.unwrap();
 
         create_tables(&pool).await;
+        migrate(&pool).await;
 
         query!(
             "\
@@ -74,26 +284,56 @@ CREATE UNIQUE INDEX IF NOT EXISTS idx_tickets       ON registration_tickets (tic
 CREATE UNIQUE INDEX IF NOT EXISTS idx_tickets_name  ON registration_tickets (name);
 CREATE UNIQUE INDEX IF NOT EXISTS idx_shorts        ON short_links          (short);
 CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (user_id);
+CREATE        INDEX IF NOT EXISTS idx_passkeys      ON webauthn_credentials (user_id);
+CREATE        INDEX IF NOT EXISTS idx_audit_event   ON audit_log            (event);
+CREATE        INDEX IF NOT EXISTS idx_sessions      ON user_sessions        (user_id);
+CREATE        INDEX IF NOT EXISTS idx_invited_by    ON users                (invited_by);
+CREATE UNIQUE INDEX IF NOT EXISTS idx_reset_tickets ON password_reset_tickets (ticket);
+CREATE        INDEX IF NOT EXISTS idx_oidc_userid   ON oidc_identities      (user_id);
 "
         )
         .execute(&pool)
         .await
         .unwrap();
 
-        let argon2 = Argon2::new_with_secret(
-            pepper,
-            argon2::Algorithm::default(),
-            argon2::Version::default(),
-            argon2::Params::default(),
+        let params = argon2::Params::new(
+            argon2_config.memory_kib,
+            argon2_config.iterations,
+            argon2_config.parallelism,
+            None,
         )
-        .unwrap();
+        .expect("invalid argon2 parameters");
+        let argon2 = peppers
+            .keys
+            .into_iter()
+            .map(|(id, pepper)| {
+                let argon2 = Argon2::new_with_secret(
+                    pepper,
+                    argon2::Algorithm::default(),
+                    argon2::Version::default(),
+                    params.clone(),
+                )
+                .unwrap();
+                (id, argon2)
+            })
+            .collect::<HashMap<_, _>>();
+        assert!(
+            argon2.contains_key(&peppers.current),
+            "there's no pepper with the current key id"
+        );
 
-        Db { pool, argon2 }
+        Db {
+            pool,
+            argon2,
+            current_pepper: peppers.current,
+            short_quota,
+            invite_quota,
+        }
     }
 
     pub async fn verify_user(&self, username: &str, password: &str) -> Option<i64> {
         let Ok(rec) = query!(
-            "SELECT id, password_hash FROM users WHERE name = ?;",
+            "SELECT id, password_hash, pepper_id, disabled FROM users WHERE name = ?;",
             username
         )
         .fetch_one(&self.pool)
@@ -102,175 +342,1510 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
             return None;
         };
 
+        // a hash made with a pepper that was removed from the config can't be verified
+        let Some(argon2) = self.argon2.get(&rec.pepper_id) else {
+            warn!(
+                "user {} has a hash made with an unknown pepper (key id {})",
+                rec.id, rec.pepper_id
+            );
+            return None;
+        };
+
         // hashes in the DB are expected to be valid
         let hash = PasswordHash::parse(&rec.password_hash, HASH_ENCODING).unwrap();
-        if self
-            .argon2
-            .verify_password(password.as_bytes(), &hash)
-            .is_ok()
-        {
+        if argon2.verify_password(password.as_bytes(), &hash).is_ok() && !rec.disabled {
+            if rec.pepper_id != self.current_pepper || self.is_outdated(&hash) {
+                self.rehash_password(rec.id, &rec.password_hash, password)
+                    .await;
+            }
+
             Some(rec.id)
         } else {
             None
         }
     }
 
-    pub async fn register_user(&self, ticket: &str, password: &str) -> Option<i64> {
+    /// Whether the hash was made with weaker parameters than the current ones.
+    fn is_outdated(&self, hash: &PasswordHash) -> bool {
+        let current = self.argon2[&self.current_pepper].params();
+        argon2::Params::try_from(hash).map_or(true, |params| {
+            params.m_cost() < current.m_cost()
+                || params.t_cost() < current.t_cost()
+                || params.p_cost() < current.p_cost()
+        })
+    }
+
+    /// Replaces the user's hash with one made with the current parameters and pepper,
+    /// unless the hash has changed in the meantime (e.g. by a concurrent password change).
+    async fn rehash_password(&self, id: i64, old_hash: &str, password: &str) {
+        let hash = self.hash_password(password);
+        query!(
+            "\
+UPDATE users SET password_hash = ?, pepper_id = ?
+WHERE id = ? AND password_hash = ?;",
+            hash,
+            self.current_pepper,
+            id,
+            old_hash
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+    }
+
+    /// Hashes with the current pepper, whose key id has to be stored alongside the hash.
+    fn hash_password(&self, password: &str) -> String {
         let salt = SaltString::generate(&mut OsRng);
-        let hash = self
-            .argon2
+        self.argon2[&self.current_pepper]
             .hash_password(password.as_bytes(), &salt)
             .unwrap()
-            .to_string();
+            .to_string()
+    }
+
+    pub async fn register_user(
+        &self,
+        ticket: &str,
+        password: &str,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
+        self.redeem_registration_ticket(ticket, self.hash_password(password), None, origin)
+            .await
+    }
+
+    /// Registers a user that logs in through an external OpenID Connect provider, and links
+    /// the identity to them. Fails if the ticket is invalid, or the identity is already linked.
+    pub async fn register_oidc_user(
+        &self,
+        ticket: &str,
+        identity: &OidcIdentity,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
+        // nobody knows this password, so it can't be logged in with until an admin resets it
+        let mut password = [0u8; 32];
+        OsRng.fill_bytes(&mut password);
+        let hash = self.hash_password(&TICKET_ENGINE.encode(password));
+
+        self.redeem_registration_ticket(ticket, hash, Some(identity), origin)
+            .await
+    }
 
+    async fn redeem_registration_ticket(
+        &self,
+        ticket: &str,
+        hash: String,
+        identity: Option<&OidcIdentity>,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
         let mut transaction = self.pool.begin().await.unwrap();
 
-        let username = if let Ok(rec) = query!(
-            "DELETE FROM registration_tickets WHERE ticket = ? RETURNING name;",
-            ticket
+        let now = unix_now();
+        let Ok(ticket) = query!(
+            "\
+DELETE FROM registration_tickets
+WHERE ticket = ? AND (expires_at IS NULL OR expires_at > ?)
+RETURNING name, issued_by, grant_admin, grant_short;",
+            ticket,
+            now
         )
         .fetch_one(&mut *transaction)
         .await
-        {
-            rec.name
-        } else {
+        else {
             return None; // rolls back the transaction
         };
 
-        match query!(
-            "INSERT INTO users (name, password_hash) VALUES (?, ?) RETURNING id;",
-            username,
-            hash
+        // whoever issued the ticket is who invited the new user
+        let Ok(rec) = query!(
+            "\
+INSERT INTO users (name, password_hash, pepper_id, invited_by) VALUES (?, ?, ?, ?) RETURNING id;",
+            ticket.name,
+            hash,
+            self.current_pepper,
+            ticket.issued_by
         )
         .fetch_one(&mut *transaction)
         .await
-        {
-            Ok(rec) => {
-                transaction.commit().await.unwrap();
-                Some(rec.id)
+        else {
+            return None; // rolls back the transaction
+        };
+
+        record_audit(
+            &mut *transaction,
+            AuditEvent::Register,
+            Some(rec.id),
+            &ticket.name,
+            origin,
+        )
+        .await;
+
+        if let Some(identity) = identity {
+            let now = unix_now();
+            if query!(
+                "\
+INSERT INTO oidc_identities (user_id, issuer, subject, linked_at) VALUES (?, ?, ?, ?);",
+                rec.id,
+                identity.issuer,
+                identity.subject,
+                now
+            )
+            .execute(&mut *transaction)
+            .await
+            .is_err()
+            {
+                return None; // rolls back the transaction
             }
-            Err(_) => None, // rolls back the transaction
         }
+
+        let grants = [
+            (ticket.grant_admin, ADMIN_ROLE),
+            (ticket.grant_short, SHORT_ROLE),
+        ]
+        .into_iter()
+        .filter_map(|(granted, name)| granted.then_some(name))
+        .collect::<Vec<_>>();
+        for role in &grants {
+            query!(
+                "\
+INSERT INTO user_roles (user_id, role_id) SELECT ?, id FROM roles WHERE name = ?;",
+                rec.id,
+                role
+            )
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+        }
+
+        if !grants.is_empty() {
+            let grants = grants.join(", ");
+            record_audit(
+                &mut *transaction,
+                AuditEvent::PermissionsChanged,
+                Some(rec.id),
+                &format!("{}: granted {grants} by ticket", ticket.name),
+                origin,
+            )
+            .await;
+        }
+
+        transaction.commit().await.unwrap();
+        Some(rec.id)
     }
 
-    pub async fn generate_registration_ticket(&self, name: &str) -> Option<String> {
+    /// Redeems a password reset ticket, setting the password of the user it was issued for
+    /// and ending all of their sessions. Returns the user's id.
+    pub async fn reset_password(
+        &self,
+        ticket: &str,
+        password: &str,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
+        let hash = self.hash_password(password);
+
         let mut transaction = self.pool.begin().await.unwrap();
 
-        if sqlx::query_scalar::<_, i64>(
-            "SELECT EXISTS(SELECT 1 FROM registration_tickets WHERE name = ?);",
+        let now = unix_now();
+        let ticket = query!(
+            "\
+DELETE FROM password_reset_tickets WHERE ticket = ? AND expires_at > ? RETURNING user_id;",
+            ticket,
+            now
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .unwrap()?;
+
+        let rec = query!(
+            "\
+UPDATE users SET password_hash = ?, pepper_id = ?, session_generation = session_generation + 1
+WHERE id = ? RETURNING name;",
+            hash,
+            self.current_pepper,
+            ticket.user_id
         )
-        .bind(name)
         .fetch_one(&mut *transaction)
         .await
+        .unwrap();
+
+        query!(
+            "DELETE FROM user_sessions WHERE user_id = ?;",
+            ticket.user_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .unwrap();
+
+        record_audit(
+            &mut *transaction,
+            AuditEvent::PasswordReset,
+            Some(ticket.user_id),
+            &rec.name,
+            origin,
+        )
+        .await;
+
+        transaction.commit().await.unwrap();
+        Some(ticket.user_id)
+    }
+
+    /// Sets a new password for the user, and invalidates all of their existing sessions.
+    pub async fn change_password(&self, id: i64, password: &str) -> bool {
+        let hash = self.hash_password(password);
+
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        let changed = query!(
+            "\
+UPDATE users SET password_hash = ?, pepper_id = ?, session_generation = session_generation + 1
+WHERE id = ? RETURNING id;",
+            hash,
+            self.current_pepper,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
         .unwrap()
+        .is_some();
+
+        query!("DELETE FROM user_sessions WHERE user_id = ?;", id)
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+
+        transaction.commit().await.unwrap();
+        changed
+    }
+
+    /// Deletes the user along with everything that belongs to them.
+    pub async fn delete_user(&self, id: i64, origin: &RequestOrigin) -> bool {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        // recorded first, while the user's name can still be looked up
+        let Some(name) = query_scalar!("SELECT name FROM users WHERE id = ?;", id)
+            .fetch_optional(&mut *transaction)
+            .await
+            .unwrap()
+        else {
+            return false;
+        };
+        record_audit(
+            &mut *transaction,
+            AuditEvent::AccountDeleted,
+            Some(id),
+            &name,
+            origin,
+        )
+        .await;
+
+        query!("DELETE FROM users WHERE id = ?;", id)
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+
+        transaction.commit().await.unwrap();
+        true
+    }
+
+    pub async fn totp_enabled(&self, user_id: i64) -> bool {
+        sqlx::query_scalar::<_, i64>("SELECT EXISTS(SELECT 1 FROM user_totp WHERE user_id = ?);")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .unwrap()
             == 1
-        {
-            return None;
-        }
+    }
 
-        if sqlx::query_scalar::<_, i64>("SELECT EXISTS(SELECT 1 FROM users WHERE name = ?);")
-            .bind(name)
-            .fetch_one(&mut *transaction)
+    /// Enables 2FA with an already verified `secret`, whose code was valid at time step `step`.
+    /// Returns a fresh set of recovery codes.
+    pub async fn enable_totp(&self, user_id: i64, secret: &str, step: i64) -> Vec<String> {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        query!(
+            "INSERT OR REPLACE INTO user_totp (user_id, secret, last_step) VALUES (?, ?, ?);",
+            user_id,
+            secret,
+            step
+        )
+        .execute(&mut *transaction)
+        .await
+        .unwrap();
+
+        let codes = self.replace_recovery_codes(&mut transaction, user_id).await;
+
+        transaction.commit().await.unwrap();
+        codes
+    }
+
+    pub async fn disable_totp(&self, user_id: i64) {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        query!("DELETE FROM user_totp WHERE user_id = ?;", user_id)
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+        query!(
+            "DELETE FROM totp_recovery_codes WHERE user_id = ?;",
+            user_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .unwrap();
+
+        transaction.commit().await.unwrap();
+    }
+
+    /// Checks a 2FA code. Every code can only be used once.
+    pub async fn check_totp(&self, user_id: i64, code: &str) -> bool {
+        let Some(rec) = query!("SELECT secret FROM user_totp WHERE user_id = ?;", user_id)
+            .fetch_optional(&self.pool)
             .await
             .unwrap()
+        else {
+            return false;
+        };
+
+        let Some(step) = crate::auth::totp::check(&rec.secret, code, unix_now()) else {
+            return false;
+        };
+
+        query!(
+            "UPDATE user_totp SET last_step = ? WHERE user_id = ? AND last_step < ? RETURNING user_id;",
+            step,
+            user_id,
+            step
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub async fn regenerate_recovery_codes(&self, user_id: i64) -> Vec<String> {
+        let mut transaction = self.pool.begin().await.unwrap();
+        let codes = self.replace_recovery_codes(&mut transaction, user_id).await;
+        transaction.commit().await.unwrap();
+
+        codes
+    }
+
+    async fn replace_recovery_codes(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        user_id: i64,
+    ) -> Vec<String> {
+        query!(
+            "DELETE FROM totp_recovery_codes WHERE user_id = ?;",
+            user_id
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        // recovery codes are random and long enough that they don't need the pepper,
+        // which lets them survive a change of it
+        let argon2 = Argon2::default();
+        let codes = crate::auth::totp::generate_recovery_codes();
+        let hashes = codes.iter().map(|code| {
+            let salt = SaltString::generate(&mut OsRng);
+            argon2
+                .hash_password(
+                    crate::auth::totp::normalize_recovery_code(code).as_bytes(),
+                    &salt,
+                )
+                .unwrap()
+                .to_string()
+        });
+
+        let mut query_builder: QueryBuilder<sqlx::Sqlite> =
+            QueryBuilder::new("INSERT INTO totp_recovery_codes(user_id, code_hash) ");
+        query_builder.push_values(hashes, |mut b, hash| {
+            b.push_bind(user_id).push_bind(hash);
+        });
+        query_builder.build().execute(&mut *conn).await.unwrap();
+
+        codes
+    }
+
+    /// Redeems a recovery code, which can't be used again afterwards.
+    pub async fn use_recovery_code(&self, user_id: i64, code: &str) -> bool {
+        let code = crate::auth::totp::normalize_recovery_code(code);
+        let argon2 = Argon2::default();
+
+        let recs = query!(
+            "SELECT id, code_hash FROM totp_recovery_codes WHERE user_id = ?;",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap();
+
+        for rec in recs {
+            // hashes in the DB are expected to be valid
+            let hash = PasswordHash::parse(&rec.code_hash, HASH_ENCODING).unwrap();
+            if argon2.verify_password(code.as_bytes(), &hash).is_ok() {
+                return query!(
+                    "DELETE FROM totp_recovery_codes WHERE id = ? RETURNING id;",
+                    rec.id
+                )
+                .fetch_optional(&self.pool)
+                .await
+                .unwrap()
+                .is_some();
+            }
+        }
+
+        false
+    }
+
+    pub async fn count_recovery_codes(&self, user_id: i64) -> i64 {
+        sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM totp_recovery_codes WHERE user_id = ?;")
+            .bind(user_id)
+            .fetch_one(&self.pool)
+            .await
+            .unwrap()
+    }
+
+    pub async fn get_passkeys(&self, user_id: i64) -> Vec<crate::auth::passkey::StoredPasskey> {
+        query_as!(
+            crate::auth::passkey::StoredPasskey,
+            "\
+SELECT id, credential_id, name, passkey, created_at, last_used_at
+FROM webauthn_credentials WHERE user_id = ? ORDER BY id ASC;",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// The most recently added passkey of any user.
+    pub async fn get_latest_passkey(&self) -> Option<crate::auth::passkey::StoredPasskey> {
+        query_as!(
+            crate::auth::passkey::StoredPasskey,
+            "\
+SELECT id, credential_id, name, passkey, created_at, last_used_at
+FROM webauthn_credentials ORDER BY id DESC LIMIT 1;"
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    pub async fn add_passkey(
+        &self,
+        user_id: i64,
+        credential_id: &str,
+        name: &str,
+        passkey: &str,
+    ) -> bool {
+        let now = unix_now();
+        query!(
+            "\
+INSERT INTO webauthn_credentials (user_id, credential_id, name, passkey, created_at)
+VALUES (?, ?, ?, ?, ?);",
+            user_id,
+            credential_id,
+            name,
+            passkey,
+            now
+        )
+        .execute(&self.pool)
+        .await
+        .is_ok()
+    }
+
+    /// Stores the passkey's state (e.g. its signature counter) after it was used to log in.
+    pub async fn update_passkey(&self, user_id: i64, credential_id: &str, passkey: &str) {
+        let now = unix_now();
+        query!(
+            "\
+UPDATE webauthn_credentials SET passkey = ?, last_used_at = ?
+WHERE user_id = ? AND credential_id = ?;",
+            passkey,
+            now,
+            user_id,
+            credential_id
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+    }
+
+    pub async fn delete_passkey(&self, user_id: i64, id: i64) -> bool {
+        query!(
+            "DELETE FROM webauthn_credentials WHERE user_id = ? AND id = ? RETURNING id;",
+            user_id,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub async fn audit(
+        &self,
+        event: AuditEvent,
+        actor: Option<i64>,
+        subject: &str,
+        origin: &RequestOrigin,
+    ) {
+        record_audit(&self.pool, event, actor, subject, origin).await;
+    }
+
+    /// The newest audit records matching the filter, newest first.
+    pub async fn get_audit_log(&self, filter: &AuditFilter, limit: i64) -> Vec<AuditRecord> {
+        let event = filter.event.map(|x| x.as_str());
+        query_as!(
+            AuditRecord,
+            r#"
+SELECT id, at, event, actor_id, actor_name, subject, ip, user_agent
+FROM audit_log
+WHERE (? IS NULL OR event = ?)
+    AND (? IS NULL OR actor_name = ? OR subject = ?)
+    AND (? IS NULL OR ip = ?)
+    AND (? IS NULL OR id < ?)
+ORDER BY id DESC LIMIT ?;"#,
+            event,
+            event,
+            filter.user,
+            filter.user,
+            filter.user,
+            filter.ip,
+            filter.ip,
+            filter.before,
+            filter.before,
+            limit
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// Records a new session for the user, returning its id and the token that identifies it.
+    pub async fn create_session(&self, user_id: i64, origin: &RequestOrigin) -> (i64, String) {
+        let now = unix_now();
+        let token = Alphanumeric.sample_string(&mut rand::thread_rng(), SESSION_TOKEN_LENGTH);
+
+        // sessions that haven't been seen for this long have expired in the session store
+        let cutoff = now - SESSION_LIFETIME;
+        query!("DELETE FROM user_sessions WHERE last_seen < ?;", cutoff)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+
+        let rec = query!(
+            "\
+INSERT INTO user_sessions (user_id, token, created_at, last_seen, ip, user_agent)
+VALUES (?, ?, ?, ?, ?, ?) RETURNING id;",
+            user_id,
+            token,
+            now,
+            now,
+            origin.ip,
+            origin.user_agent
+        )
+        .fetch_one(&self.pool)
+        .await
+        .unwrap();
+
+        (rec.id, token)
+    }
+
+    /// Looks up the session with the given token, and notes that it's still in use.
+    /// Returns `None` if the session was revoked.
+    pub async fn touch_session(
+        &self,
+        user_id: i64,
+        token: &str,
+        origin: &RequestOrigin,
+    ) -> Option<i64> {
+        let rec = query!(
+            "SELECT id, last_seen FROM user_sessions WHERE user_id = ? AND token = ?;",
+            user_id,
+            token
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()?;
+
+        // this runs on every request, so the DB is only written to every once in a while
+        let now = unix_now();
+        if now - rec.last_seen >= SESSION_TOUCH_INTERVAL {
+            query!(
+                "UPDATE user_sessions SET last_seen = ?, ip = ?, user_agent = ? WHERE id = ?;",
+                now,
+                origin.ip,
+                origin.user_agent,
+                rec.id
+            )
+            .execute(&self.pool)
+            .await
+            .unwrap();
+        }
+
+        Some(rec.id)
+    }
+
+    pub async fn get_sessions(&self, user_id: i64) -> Vec<crate::account::ActiveSession> {
+        query_as!(
+            crate::account::ActiveSession,
+            "\
+SELECT id, created_at, last_seen, ip, user_agent FROM user_sessions
+WHERE user_id = ? ORDER BY last_seen DESC;",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    pub async fn revoke_session(&self, user_id: i64, id: i64) -> bool {
+        query!(
+            "DELETE FROM user_sessions WHERE user_id = ? AND id = ? RETURNING id;",
+            user_id,
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub async fn revoke_other_sessions(&self, user_id: i64, keep: i64) {
+        query!(
+            "DELETE FROM user_sessions WHERE user_id = ? AND id != ?;",
+            user_id,
+            keep
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+    }
+
+    pub async fn delete_session(&self, token: &str) {
+        query!("DELETE FROM user_sessions WHERE token = ?;", token)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+    }
+
+    /// The serialized state of a session in the SQLite session store, if it hasn't expired.
+    pub async fn load_session_state(&self, key: &str) -> Option<String> {
+        let now = unix_now();
+        query!(
+            "SELECT state FROM session_states WHERE key = ? AND expires_at > ?;",
+            key,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.state)
+    }
+
+    /// Returns false if the key is already in use.
+    pub async fn save_session_state(&self, key: &str, state: &str, expires_at: i64) -> bool {
+        query!(
+            "INSERT INTO session_states (key, state, expires_at) VALUES (?, ?, ?);",
+            key,
+            state,
+            expires_at
+        )
+        .execute(&self.pool)
+        .await
+        .is_ok()
+    }
+
+    /// Returns false if there's no such session, or if it has expired.
+    pub async fn update_session_state(&self, key: &str, state: &str, expires_at: i64) -> bool {
+        let now = unix_now();
+        query!(
+            "\
+UPDATE session_states SET state = ?, expires_at = ?
+WHERE key = ? AND expires_at > ? RETURNING key;",
+            state,
+            expires_at,
+            key,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub async fn update_session_state_ttl(&self, key: &str, expires_at: i64) {
+        query!(
+            "UPDATE session_states SET expires_at = ? WHERE key = ?;",
+            expires_at,
+            key
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+    }
+
+    pub async fn delete_session_state(&self, key: &str) {
+        query!("DELETE FROM session_states WHERE key = ?;", key)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+    }
+
+    pub async fn purge_session_states(&self) {
+        let now = unix_now();
+        query!("DELETE FROM session_states WHERE expires_at <= ?;", now)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+    }
+
+    /// Sessions created before the user's current session generation are no longer valid.
+    pub async fn get_session_generation(&self, id: i64) -> Option<i64> {
+        query!("SELECT session_generation FROM users WHERE id = ?;", id)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|x| x.session_generation)
+    }
+
+    /// Issues a registration ticket for `name` which is valid until `expires_at` (a unix timestamp),
+    /// and grants `perms` to whoever redeems it.
+    pub async fn generate_registration_ticket(
+        &self,
+        name: &str,
+        issued_by: Option<i64>,
+        expires_at: i64,
+        perms: UserPermissions,
+    ) -> Option<String> {
+        let mut transaction = self.pool.begin().await.unwrap();
+        let ticket = self
+            .insert_registration_ticket(&mut transaction, name, issued_by, expires_at, perms)
+            .await?;
+
+        transaction.commit().await.unwrap();
+        Some(ticket)
+    }
+
+    /// Issues a ticket for someone the user invites, which counts against their invite quota.
+    /// Invitees start out without any capabilities.
+    pub async fn generate_invite_ticket(
+        &self,
+        name: &str,
+        inviter: i64,
+        expires_at: i64,
+    ) -> Result<String, InviteError> {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        // this takes the write lock before anything is counted, so concurrent invites queue up
+        let ticket = self
+            .insert_registration_ticket(
+                &mut transaction,
+                name,
+                Some(inviter),
+                expires_at,
+                UserPermissions::default(),
+            )
+            .await
+            .ok_or(InviteError::NameTaken)?;
+
+        // the new ticket is counted as well
+        if self.invites_used(&mut transaction, inviter).await > self.invite_quota.max_invites {
+            return Err(InviteError::NoInvitesLeft); // rolls back the transaction
+        }
+
+        transaction.commit().await.unwrap();
+        Ok(ticket)
+    }
+
+    async fn insert_registration_ticket(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        name: &str,
+        issued_by: Option<i64>,
+        expires_at: i64,
+        perms: UserPermissions,
+    ) -> Option<String> {
+        // expired tickets shouldn't keep their names reserved
+        let now = unix_now();
+        query!(
+            "DELETE FROM registration_tickets WHERE expires_at <= ?;",
+            now
+        )
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+        if query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM registration_tickets WHERE name = ?) AS "taken!: bool";"#,
+            name
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap()
+        {
+            return None;
+        }
+
+        if query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM users WHERE name = ?) AS "taken!: bool";"#,
+            name
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap()
+        {
+            return None;
+        }
+
+        // *theoretically*, there is an astronomically small chance of a collision here,
+        // so we have a loop.
+        loop {
+            let mut bytes = [0u8; 128];
+            OsRng.fill_bytes(&mut bytes);
+            let ticket = TICKET_ENGINE.encode(bytes);
+
+            let grant_admin = perms.is_admin();
+            let grant_short = perms.is_short();
+            match query!(
+                "\
+INSERT INTO registration_tickets
+    (name, ticket, issued_by, issued_at, expires_at, grant_admin, grant_short)
+VALUES (?, ?, ?, ?, ?, ?, ?);",
+                name,
+                ticket,
+                issued_by,
+                now,
+                expires_at,
+                grant_admin,
+                grant_short
+            )
+            .execute(&mut *conn)
+            .await
+            {
+                Ok(_) => break Some(ticket),
+                // someone else got a ticket for the name in the meantime
+                Err(sqlx::Error::Database(err))
+                    if err.is_unique_violation()
+                        && err.message().contains("registration_tickets.name") =>
+                {
+                    break None
+                }
+                // a ticket collision
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => continue,
+                Err(err) => panic!("couldn't issue a registration ticket: {err}"),
+            }
+        }
+    }
+
+    pub async fn get_registration_tickets(&self) -> Vec<crate::admin::RegistrationTicket> {
+        let now = unix_now();
+        query_as!(
+            crate::admin::RegistrationTicket,
+            r#"
+SELECT
+    registration_tickets.id,
+    registration_tickets.name,
+    ticket,
+    users.name AS "issued_by?",
+    issued_at,
+    expires_at,
+    grant_admin,
+    grant_short
+FROM registration_tickets
+LEFT JOIN users ON users.id = registration_tickets.issued_by
+WHERE expires_at IS NULL OR expires_at > ?
+ORDER BY registration_tickets.id ASC;"#,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// The name an outstanding registration ticket was issued for.
+    pub async fn get_ticket_name(&self, ticket: &str) -> Option<String> {
+        let now = unix_now();
+        query!(
+            "\
+SELECT name FROM registration_tickets WHERE ticket = ? AND (expires_at IS NULL OR expires_at > ?);",
+            ticket,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.name)
+    }
+
+    /// The name of the user an outstanding password reset ticket was issued for.
+    pub async fn get_reset_ticket_name(&self, ticket: &str) -> Option<String> {
+        let now = unix_now();
+        query!(
+            "\
+SELECT users.name FROM password_reset_tickets JOIN users ON users.id = password_reset_tickets.user_id
+WHERE ticket = ? AND expires_at > ?;",
+            ticket,
+            now
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.name)
+    }
+
+    /// Returns the name the revoked ticket was issued for, if it existed.
+    pub async fn revoke_registration_ticket(&self, id: i64) -> Option<String> {
+        query!(
+            "DELETE FROM registration_tickets WHERE id = ? RETURNING name;",
+            id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.name)
+    }
+
+    /// Issues a password reset ticket for the user which is valid until `expires_at`
+    /// (a unix timestamp). Any earlier reset ticket for the user stops working.
+    pub async fn generate_reset_ticket(
+        &self,
+        user_id: i64,
+        issued_by: Option<i64>,
+        expires_at: i64,
+    ) -> Option<String> {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        query!(
+            "DELETE FROM password_reset_tickets WHERE user_id = ?;",
+            user_id
+        )
+        .execute(&mut *transaction)
+        .await
+        .unwrap();
+
+        let now = unix_now();
+        loop {
+            let mut bytes = [0u8; 128];
+            OsRng.fill_bytes(&mut bytes);
+            let ticket = TICKET_ENGINE.encode(bytes);
+
+            match query!(
+                "\
+INSERT INTO password_reset_tickets (user_id, ticket, issued_by, issued_at, expires_at)
+VALUES (?, ?, ?, ?, ?);",
+                user_id,
+                ticket,
+                issued_by,
+                now,
+                expires_at
+            )
+            .execute(&mut *transaction)
+            .await
+            {
+                Ok(_) => {
+                    transaction.commit().await.unwrap();
+                    break Some(ticket);
+                }
+                // the user doesn't exist
+                Err(sqlx::Error::Database(err)) if err.is_foreign_key_violation() => break None,
+                // a ticket collision, see insert_registration_ticket
+                Err(sqlx::Error::Database(err)) if err.is_unique_violation() => continue,
+                Err(err) => panic!("couldn't issue a reset ticket: {err}"),
+            }
+        }
+    }
+
+    /// The user an external identity is linked to.
+    pub async fn get_oidc_user(&self, identity: &OidcIdentity) -> Option<i64> {
+        query!(
+            "SELECT user_id FROM oidc_identities WHERE issuer = ? AND subject = ?;",
+            identity.issuer,
+            identity.subject
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.user_id)
+    }
+
+    /// Whether the user has an external identity linked to them.
+    pub async fn has_oidc_identity(&self, user_id: i64) -> bool {
+        query_scalar!(
+            r#"SELECT EXISTS(SELECT 1 FROM oidc_identities WHERE user_id = ?) AS "linked!: bool";"#,
+            user_id
+        )
+        .fetch_one(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// Fails if the identity is already linked to a user.
+    pub async fn link_oidc_identity(&self, user_id: i64, identity: &OidcIdentity) -> bool {
+        let now = unix_now();
+        query!(
+            "\
+INSERT INTO oidc_identities (user_id, issuer, subject, linked_at) VALUES (?, ?, ?, ?);",
+            user_id,
+            identity.issuer,
+            identity.subject,
+            now
+        )
+        .execute(&self.pool)
+        .await
+        .is_ok()
+    }
+
+    /// The outstanding tickets that the user issued.
+    pub async fn get_issued_tickets(&self, user_id: i64) -> Vec<crate::admin::RegistrationTicket> {
+        let now = unix_now();
+        query_as!(
+            crate::admin::RegistrationTicket,
+            r#"
+SELECT
+    registration_tickets.id,
+    registration_tickets.name,
+    ticket,
+    users.name AS "issued_by?",
+    issued_at,
+    expires_at,
+    grant_admin,
+    grant_short
+FROM registration_tickets
+LEFT JOIN users ON users.id = registration_tickets.issued_by
+WHERE registration_tickets.issued_by = ? AND (expires_at IS NULL OR expires_at > ?)
+ORDER BY registration_tickets.id ASC;"#,
+            user_id,
+            now
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// Like [`Db::revoke_registration_ticket`], but only if the ticket was issued by the user.
+    pub async fn revoke_issued_ticket(&self, user_id: i64, id: i64) -> Option<String> {
+        query!(
+            "DELETE FROM registration_tickets WHERE id = ? AND issued_by = ? RETURNING name;",
+            id,
+            user_id
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .map(|rec| rec.name)
+    }
+
+    /// The names of the users who registered with a ticket issued by the user.
+    pub async fn get_invitees(&self, user_id: i64) -> Vec<String> {
+        query!(
+            "SELECT name FROM users WHERE invited_by = ? ORDER BY id ASC;",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rec| rec.name)
+        .collect()
+    }
+
+    /// How many more people the user may invite.
+    pub async fn get_invites_left(&self, user_id: i64) -> i64 {
+        let mut conn = self.pool.acquire().await.unwrap();
+        let used = self.invites_used(&mut conn, user_id).await;
+
+        (self.invite_quota.max_invites - used).max(0)
+    }
+
+    /// Outstanding tickets the user issued, and users who redeemed one.
+    async fn invites_used(&self, conn: &mut sqlx::SqliteConnection, user_id: i64) -> i64 {
+        let now = unix_now();
+        query_scalar!(
+            r#"
+SELECT
+    (SELECT COUNT(*) FROM registration_tickets
+    WHERE issued_by = ? AND (expires_at IS NULL OR expires_at > ?))
+    + (SELECT COUNT(*) FROM users WHERE invited_by = ?) AS "used!: i64";"#,
+            user_id,
+            now,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap()
+    }
+
+    /// All users, along with who invited them.
+    pub async fn get_invite_tree(&self) -> Vec<InvitedUser> {
+        query_as!(
+            InvitedUser,
+            "SELECT id, name, invited_by, disabled FROM users ORDER BY id ASC;"
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    /// The ids and names of the user and everyone they invited, directly or indirectly.
+    pub async fn get_invite_branch(&self, id: i64) -> Vec<(i64, String)> {
+        query!(
+            r#"
+WITH RECURSIVE branch(id) AS (
+    SELECT id FROM users WHERE id = ?
+    UNION
+    SELECT users.id FROM users JOIN branch ON users.invited_by = branch.id
+)
+SELECT users.id AS "id!", users.name FROM users JOIN branch ON branch.id = users.id
+ORDER BY users.id ASC;"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rec| (rec.id, rec.name))
+        .collect()
+    }
+
+    /// Disables all of the given users, ends their sessions, and revokes the tickets they issued.
+    /// Returns the names the revoked tickets were issued for.
+    pub async fn revoke_invite_branch(&self, ids: &[i64]) -> Vec<String> {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        let mut tickets = vec![];
+        for id in ids {
+            query!(
+                "\
+UPDATE users SET disabled = TRUE, session_generation = session_generation + 1 WHERE id = ?;",
+                id
+            )
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+
+            query!("DELETE FROM user_sessions WHERE user_id = ?;", id)
+                .execute(&mut *transaction)
+                .await
+                .unwrap();
+
+            tickets.extend(
+                query!(
+                    "DELETE FROM registration_tickets WHERE issued_by = ? RETURNING name;",
+                    id
+                )
+                .fetch_all(&mut *transaction)
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|rec| rec.name),
+            );
+        }
+
+        transaction.commit().await.unwrap();
+        tickets
+    }
+
+    pub async fn get_username(&self, id: i64) -> Option<String> {
+        query!("SELECT name FROM users WHERE id = ?;", id)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|x| x.name)
+    }
+
+    pub async fn get_visitors(&self) -> i64 {
+        query!("SELECT visitors FROM visitors;")
+            .fetch_one(&self.pool)
+            .await
+            .unwrap()
+            .visitors
+    }
+
+    pub async fn set_visitors(&self, visitors: i64) {
+        query!("UPDATE visitors SET visitors = ?;", visitors)
+            .execute(&self.pool)
+            .await
+            .unwrap();
+    }
+
+    pub async fn get_messages(&self) -> Vec<GameMessage> {
+        let messages = query!("SELECT * FROM messages ORDER BY id ASC;")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap();
+
+        messages
+            .into_iter()
+            .map(|rec| GameMessage {
+                name: rec.name,
+                content: rec.content,
+                ip: rec.ip.parse().unwrap(),
+            })
+            .collect()
+    }
+
+    pub async fn set_messages(&self, messages: &[GameMessage]) {
+        query!("DELETE FROM messages;")
+            .execute(&self.pool)
+            .await
+            .unwrap();
+
+        if !messages.is_empty() {
+            let mut query_builder: QueryBuilder<sqlx::Sqlite> =
+                QueryBuilder::new("INSERT INTO messages(name, content, ip) ");
+            query_builder.push_values(messages, |mut b, message| {
+                b.push_bind(&message.name)
+                    .push_bind(&message.content)
+                    .push_bind(message.ip.to_string());
+            });
+
+            query_builder.build().execute(&self.pool).await.unwrap();
+        }
+    }
+
+    /// All users, along with the ids of their roles.
+    pub async fn get_users(&self) -> Vec<crate::admin::ManagedUser> {
+        let mut roles: HashMap<i64, Vec<i64>> = HashMap::new();
+        for rec in query!("SELECT user_id, role_id FROM user_roles;")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap()
+        {
+            roles.entry(rec.user_id).or_default().push(rec.role_id);
+        }
+
+        query!("SELECT id, name, disabled FROM users ORDER BY id ASC;")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|rec| crate::admin::ManagedUser {
+                roles: roles.remove(&rec.id).unwrap_or_default(),
+                id: rec.id,
+                name: rec.name,
+                disabled: rec.disabled,
+            })
+            .collect()
+    }
+
+    /// Replaces all of the user's roles.
+    pub async fn set_user_roles(&self, user_id: i64, role_ids: &[i64]) -> bool {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        query!("DELETE FROM user_roles WHERE user_id = ?;", user_id)
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+
+        if !role_ids.is_empty() {
+            let mut builder = QueryBuilder::new("INSERT INTO user_roles (user_id, role_id) ");
+            builder.push_values(role_ids, |mut b, role_id| {
+                b.push_bind(user_id).push_bind(role_id);
+            });
+            if builder.build().execute(&mut *transaction).await.is_err() {
+                return false; // rolls back the transaction
+            }
+        }
+
+        transaction.commit().await.unwrap();
+        true
+    }
+
+    pub async fn get_roles(&self) -> Vec<Role> {
+        let mut capabilities: HashMap<i64, UserPermissions> = HashMap::new();
+        for rec in query!("SELECT role_id, capability FROM role_capabilities;")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap()
+        {
+            // capabilities that no longer exist are simply ignored
+            if let Ok(capability) = rec.capability.parse() {
+                capabilities
+                    .entry(rec.role_id)
+                    .or_default()
+                    .set(capability, true);
+            }
+        }
+
+        query!("SELECT id, name FROM roles ORDER BY id ASC;")
+            .fetch_all(&self.pool)
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|rec| Role {
+                capabilities: capabilities.remove(&rec.id).unwrap_or_default(),
+                id: rec.id,
+                name: rec.name,
+            })
+            .collect()
+    }
+
+    /// Fails if the name is already taken by another role.
+    pub async fn create_role(&self, name: &str, capabilities: UserPermissions) -> bool {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        let Ok(rec) = query!("INSERT INTO roles (name) VALUES (?) RETURNING id;", name)
+            .fetch_one(&mut *transaction)
+            .await
+        else {
+            return false; // rolls back the transaction
+        };
+
+        replace_role_capabilities(&mut transaction, rec.id, capabilities).await;
+
+        transaction.commit().await.unwrap();
+        true
+    }
+
+    pub async fn set_role_capabilities(&self, role_id: i64, capabilities: UserPermissions) {
+        let mut transaction = self.pool.begin().await.unwrap();
+        replace_role_capabilities(&mut transaction, role_id, capabilities).await;
+        transaction.commit().await.unwrap();
+    }
+
+    pub async fn delete_role(&self, role_id: i64) -> bool {
+        query!("DELETE FROM roles WHERE id = ? RETURNING id;", role_id)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    /// Fails if the name is taken by another user or by an outstanding ticket.
+    pub async fn rename_user(&self, id: i64, name: &str) -> bool {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        // a user with a ticket's name would make the ticket unusable
+        let now = unix_now();
+        if sqlx::query_scalar::<_, i64>(
+            "\
+SELECT EXISTS(SELECT 1 FROM registration_tickets
+WHERE name = ? AND (expires_at IS NULL OR expires_at > ?));",
+        )
+        .bind(name)
+        .bind(now)
+        .fetch_one(&mut *transaction)
+        .await
+        .unwrap()
             == 1
         {
-            return None;
+            return false;
         }
 
-        // *theoretically*, there is an astronomically small chance of a collision here,
-        // so we have a loop.
-        loop {
-            let mut bytes = [0u8; 128];
-            OsRng.fill_bytes(&mut bytes);
-            let ticket = TICKET_ENGINE.encode(bytes);
+        let renamed = query!(
+            "UPDATE users SET name = ? WHERE id = ? RETURNING id;",
+            name,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .is_ok_and(|rec| rec.is_some());
 
-            if query!(
-                "INSERT INTO registration_tickets (name, ticket) VALUES (?, ?);",
-                name,
-                ticket
-            )
-            .execute(&mut *transaction)
-            .await
-            .is_ok()
-            {
-                transaction.commit().await.unwrap();
-                break Some(ticket);
-            }
-        }
+        transaction.commit().await.unwrap();
+        renamed
     }
 
-    pub async fn get_username(&self, id: i64) -> Option<String> {
-        query!("SELECT name FROM users WHERE id = ?;", id)
-            .fetch_optional(&self.pool)
+    /// Disabled users can't log in, and disabling a user ends all of their sessions.
+    pub async fn set_disabled(&self, id: i64, disabled: bool) -> bool {
+        let mut transaction = self.pool.begin().await.unwrap();
+
+        let changed = query!(
+            "\
+UPDATE users SET disabled = ?, session_generation = session_generation + 1
+WHERE id = ? RETURNING id;",
+            disabled,
+            id
+        )
+        .fetch_optional(&mut *transaction)
+        .await
+        .unwrap()
+        .is_some();
+
+        query!("DELETE FROM user_sessions WHERE user_id = ?;", id)
+            .execute(&mut *transaction)
             .await
-            .unwrap()
-            .map(|x| x.name)
+            .unwrap();
+
+        transaction.commit().await.unwrap();
+        changed
     }
 
-    pub async fn get_visitors(&self) -> i64 {
-        query!("SELECT visitors FROM visitors;")
-            .fetch_one(&self.pool)
+    pub async fn is_disabled(&self, id: i64) -> bool {
+        query!("SELECT disabled FROM users WHERE id = ?;", id)
+            .fetch_optional(&self.pool)
             .await
             .unwrap()
-            .visitors
+            .map_or(true, |rec| rec.disabled)
     }
 
-    pub async fn set_visitors(&self, visitors: i64) {
-        query!("UPDATE visitors SET visitors = ?;", visitors)
-            .execute(&self.pool)
-            .await
-            .unwrap();
+    /// All capabilities the user has through their roles.
+    pub async fn get_permissions(&self, id: i64) -> UserPermissions {
+        query!(
+            "\
+SELECT DISTINCT capability FROM user_roles
+JOIN role_capabilities ON role_capabilities.role_id = user_roles.role_id
+WHERE user_roles.user_id = ?;",
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+        .into_iter()
+        // capabilities that no longer exist are simply ignored
+        .filter_map(|rec| rec.capability.parse().ok())
+        .collect()
     }
 
-    pub async fn get_messages(&self) -> Vec<GameMessage> {
-        let messages = query!("SELECT * FROM messages ORDER BY id ASC;")
+    pub async fn get_role_ids(&self, id: i64) -> Vec<i64> {
+        query!("SELECT role_id FROM user_roles WHERE user_id = ?;", id)
             .fetch_all(&self.pool)
             .await
-            .unwrap();
-
-        messages
+            .unwrap()
             .into_iter()
-            .map(|rec| GameMessage {
-                name: rec.name,
-                content: rec.content,
-                ip: rec.ip.parse().unwrap(),
-            })
+            .map(|rec| rec.role_id)
             .collect()
     }
 
-    pub async fn set_messages(&self, messages: &[GameMessage]) {
-        query!("DELETE FROM messages;")
-            .execute(&self.pool)
-            .await
-            .unwrap();
-
-        if !messages.is_empty() {
-            let mut query_builder: QueryBuilder<sqlx::Sqlite> =
-                QueryBuilder::new("INSERT INTO messages(name, content, ip) ");
-            query_builder.push_values(messages, |mut b, message| {
-                b.push_bind(&message.name)
-                    .push_bind(&message.content)
-                    .push_bind(message.ip.to_string());
-            });
+    /// The user's name, session generation and permissions, all in one query.
+    pub async fn get_user_record(&self, id: i64) -> Option<UserRecord> {
+        let rows = query!(
+            r#"
+SELECT users.name, users.session_generation, role_capabilities.capability AS "capability?"
+FROM users
+LEFT JOIN user_roles ON user_roles.user_id = users.id
+LEFT JOIN role_capabilities ON role_capabilities.role_id = user_roles.role_id
+WHERE users.id = ?;"#,
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap();
 
-            query_builder.build().execute(&self.pool).await.unwrap();
-        }
+        let first = rows.first()?;
+        Some(UserRecord {
+            name: first.name.clone(),
+            session_generation: first.session_generation,
+            perms: rows
+                .iter()
+                .filter_map(|rec| rec.capability.as_deref()?.parse().ok())
+                .collect(),
+        })
     }
 
-    pub async fn get_permissions(&self, id: i64) -> Option<UserPermissions> {
-        query!("SELECT * FROM user_permissions WHERE user_id = ?;", id)
-            .fetch_optional(&self.pool)
-            .await
-            .unwrap()
-            .map(|rec| {
-                let mut perms = UserPermissions::default();
-
-                perms.admin(rec.admin);
-                perms.short(rec.short);
-
-                perms
-            })
+    /// The names of the user's roles.
+    pub async fn get_role_names(&self, id: i64) -> Vec<String> {
+        query!(
+            "\
+SELECT roles.name FROM user_roles JOIN roles ON roles.id = user_roles.role_id
+WHERE user_roles.user_id = ? ORDER BY roles.name ASC;",
+            id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+        .into_iter()
+        .map(|rec| rec.name)
+        .collect()
     }
 
     pub async fn create_short_link(
@@ -278,7 +1853,7 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
         user_id: i64,
         link: &str,
         short: Option<&str>,
-    ) -> Option<String> {
+    ) -> Result<String, ShortLinkError> {
         let mut transaction = self.pool.begin().await.unwrap();
 
         if sqlx::query_scalar::<_, i64>("SELECT EXISTS(SELECT 1 FROM users WHERE id = ?);")
@@ -288,15 +1863,25 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
             .unwrap()
             == 0
         {
-            return None;
+            return Err(ShortLinkError::NoSuchUser);
+        }
+
+        let now = unix_now();
+        let usage = self.short_usage(&mut transaction, user_id, now).await;
+        if usage.links >= usage.quota.max_links {
+            return Err(ShortLinkError::TooManyLinks(usage.quota.max_links));
+        }
+        if usage.links_today >= usage.quota.max_links_per_day {
+            return Err(ShortLinkError::TooManyToday(usage.quota.max_links_per_day));
         }
 
         let short = if let Some(short) = short {
             let ret = query!(
-                "INSERT INTO short_links (user_id, url, short) VALUES (?, ?, ?);",
+                "INSERT INTO short_links (user_id, url, short, created_at) VALUES (?, ?, ?, ?);",
                 user_id,
                 link,
-                short
+                short,
+                now
             )
             .execute(&mut *transaction)
             .await;
@@ -304,17 +1889,18 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
             if ret.is_ok() {
                 short.to_owned()
             } else {
-                return None;
+                return Err(ShortLinkError::Taken);
             }
         } else {
             let mut short = String::new();
             loop {
                 generate_short(&mut short);
                 let res = query!(
-                    "INSERT INTO short_links (user_id, url, short) VALUES (?, ?, ?)",
+                    "INSERT INTO short_links (user_id, url, short, created_at) VALUES (?, ?, ?, ?)",
                     user_id,
                     link,
-                    short
+                    short,
+                    now
                 )
                 .execute(&mut *transaction)
                 .await;
@@ -326,7 +1912,55 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
         };
 
         transaction.commit().await.unwrap();
-        Some(short)
+        Ok(short)
+    }
+
+    pub async fn get_short_usage(&self, user_id: i64) -> ShortUsage {
+        let mut conn = self.pool.acquire().await.unwrap();
+        self.short_usage(&mut conn, user_id, unix_now()).await
+    }
+
+    async fn short_usage(
+        &self,
+        conn: &mut sqlx::SqliteConnection,
+        user_id: i64,
+        now: i64,
+    ) -> ShortUsage {
+        let quota = query!(
+            "SELECT max_links, max_links_per_day FROM short_link_quotas WHERE user_id = ?;",
+            user_id
+        )
+        .fetch_optional(&mut *conn)
+        .await
+        .unwrap()
+        .map(|rec| ShortQuota {
+            max_links: rec.max_links.unwrap_or(self.short_quota.max_links),
+            max_links_per_day: rec
+                .max_links_per_day
+                .unwrap_or(self.short_quota.max_links_per_day),
+        })
+        .unwrap_or(self.short_quota);
+
+        let day_ago = now - DAY;
+        let rec = query!(
+            r#"
+SELECT
+    COUNT(*)                                AS "links!: i64",
+    COALESCE(SUM(created_at > ?), 0)        AS "links_today!: i64"
+FROM short_links
+WHERE user_id = ?;"#,
+            day_ago,
+            user_id
+        )
+        .fetch_one(&mut *conn)
+        .await
+        .unwrap();
+
+        ShortUsage {
+            links: rec.links,
+            links_today: rec.links_today,
+            quota,
+        }
     }
 
     pub async fn get_short_link(&self, link: &str, peer_addr: IpAddr) -> Option<String> {
@@ -354,7 +1988,75 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
     pub async fn get_links(&self, user_id: i64) -> Vec<crate::short::Link> {
         query_as!(
             crate::short::Link,
-            "SELECT id, url, short FROM short_links WHERE user_id = ? ORDER BY id ASC;",
+            "SELECT id, url, short, title, public, created_at FROM short_links WHERE user_id = ? ORDER BY id ASC;",
+            user_id
+        )
+        .fetch_all(&self.pool)
+        .await
+        .unwrap()
+    }
+
+    pub async fn set_link_visibility(
+        &self,
+        user_id: i64,
+        short: &str,
+        title: Option<&str>,
+        public: bool,
+    ) -> bool {
+        query!(
+            "UPDATE short_links SET title = ?, public = ? WHERE user_id = ? AND short = ? RETURNING id;",
+            title,
+            public,
+            user_id,
+            short
+        )
+        .fetch_optional(&self.pool)
+        .await
+        .unwrap()
+        .is_some()
+    }
+
+    pub async fn get_user_id(&self, name: &str) -> Option<i64> {
+        query!("SELECT id FROM users WHERE name = ?;", name)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|x| x.id)
+    }
+
+    pub async fn get_public_clicks(&self, user_id: i64) -> bool {
+        query!("SELECT public_clicks FROM users WHERE id = ?;", user_id)
+            .fetch_optional(&self.pool)
+            .await
+            .unwrap()
+            .map(|x| x.public_clicks)
+            .unwrap_or(false)
+    }
+
+    pub async fn set_public_clicks(&self, user_id: i64, public_clicks: bool) {
+        query!(
+            "UPDATE users SET public_clicks = ? WHERE id = ?;",
+            public_clicks,
+            user_id
+        )
+        .execute(&self.pool)
+        .await
+        .unwrap();
+    }
+
+    pub async fn get_link_stats(&self, user_id: i64) -> Vec<crate::short::LinkStats> {
+        query_as!(
+            crate::short::LinkStats,
+            r#"
+SELECT
+    short_links.id                              AS "link_id!",
+    COUNT(short_link_stats.id)                  AS "clicks!: i64",
+    COUNT(DISTINCT short_link_stats.peer_addr)  AS "unique_visitors!: i64"
+FROM short_links
+LEFT JOIN short_link_stats ON short_link_stats.link_id = short_links.id
+WHERE short_links.user_id = ?
+GROUP BY short_links.id
+ORDER BY short_links.id ASC;"#,
             user_id
         )
         .fetch_all(&self.pool)
@@ -375,6 +2077,53 @@ CREATE        INDEX IF NOT EXISTS idx_shorts_userid ON short_links          (use
     }
 }
 
+#[cfg(not(feature = "prepare_db"))]
+async fn replace_role_capabilities(
+    conn: &mut sqlx::SqliteConnection,
+    role_id: i64,
+    capabilities: UserPermissions,
+) {
+    query!("DELETE FROM role_capabilities WHERE role_id = ?;", role_id)
+        .execute(&mut *conn)
+        .await
+        .unwrap();
+
+    if capabilities != UserPermissions::default() {
+        let mut builder = QueryBuilder::new("INSERT INTO role_capabilities (role_id, capability) ");
+        builder.push_values(capabilities.capabilities(), |mut b, capability| {
+            b.push_bind(role_id).push_bind(capability.as_str());
+        });
+        builder.build().execute(&mut *conn).await.unwrap();
+    }
+}
+
+#[cfg(not(feature = "prepare_db"))]
+async fn record_audit(
+    conn: impl sqlx::SqliteExecutor<'_>,
+    event: AuditEvent,
+    actor: Option<i64>,
+    subject: &str,
+    origin: &RequestOrigin,
+) {
+    let now = unix_now();
+    let event = event.as_str();
+    query!(
+        "\
+INSERT INTO audit_log (at, event, actor_id, actor_name, subject, ip, user_agent)
+VALUES (?, ?, ?, (SELECT name FROM users WHERE id = ?), ?, ?, ?);",
+        now,
+        event,
+        actor,
+        actor,
+        subject,
+        origin.ip,
+        origin.user_agent
+    )
+    .execute(conn)
+    .await
+    .unwrap();
+}
+
 const SHORT_LINK_LENGTH: usize = 5;
 
 fn generate_short(short: &mut String) {
@@ -399,30 +2148,138 @@ CREATE TABLE IF NOT EXISTS visitors(
 );
 
 CREATE TABLE IF NOT EXISTS users(
-    id              INTEGER NOT NULL PRIMARY KEY,
-    name            TEXT    NOT NULL UNIQUE,
-    password_hash   TEXT    NOT NULL
+    id                  INTEGER NOT NULL PRIMARY KEY,
+    name                TEXT    NOT NULL UNIQUE,
+    password_hash       TEXT    NOT NULL,
+    pepper_id           INTEGER NOT NULL DEFAULT 0,
+    public_clicks       BOOLEAN NOT NULL DEFAULT FALSE,
+    session_generation  INTEGER NOT NULL DEFAULT 0,
+    disabled            BOOLEAN NOT NULL DEFAULT FALSE,
+    invited_by          INTEGER REFERENCES users(id) ON DELETE SET NULL ON UPDATE CASCADE
+);
+
+CREATE TABLE IF NOT EXISTS roles(
+    id      INTEGER NOT NULL PRIMARY KEY,
+    name    TEXT    NOT NULL UNIQUE
+);
+
+CREATE TABLE IF NOT EXISTS role_capabilities(
+    role_id     INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    capability  TEXT    NOT NULL,
+    PRIMARY KEY (role_id, capability)
 );
 
-CREATE TABLE IF NOT EXISTS user_permissions(
-    user_id INTEGER NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
-    admin   BOOLEAN NOT NULL,
-    short   BOOLEAN NOT NULL
+CREATE TABLE IF NOT EXISTS user_roles(
+    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    role_id INTEGER NOT NULL REFERENCES roles(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    PRIMARY KEY (user_id, role_id)
 );
 
 CREATE TABLE IF NOT EXISTS registration_tickets(
-    id      INTEGER NOT NULL PRIMARY KEY,
-    name    TEXT    NOT NULl UNIQUE,
-    ticket  TEXT    NOT NULL UNIQUE
+    id          INTEGER NOT NULL PRIMARY KEY,
+    name        TEXT    NOT NULl UNIQUE,
+    ticket      TEXT    NOT NULL UNIQUE,
+    issued_by   INTEGER REFERENCES users(id) ON DELETE SET NULL ON UPDATE CASCADE,
+    issued_at   INTEGER NOT NULL DEFAULT 0,
+    expires_at  INTEGER,
+    grant_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    grant_short BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS password_reset_tickets(
+    id          INTEGER NOT NULL PRIMARY KEY,
+    user_id     INTEGER NOT NULL UNIQUE REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    ticket      TEXT    NOT NULL UNIQUE,
+    issued_by   INTEGER REFERENCES users(id) ON DELETE SET NULL ON UPDATE CASCADE,
+    issued_at   INTEGER NOT NULL,
+    expires_at  INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS oidc_identities(
+    id          INTEGER NOT NULL PRIMARY KEY,
+    user_id     INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    issuer      TEXT    NOT NULL,
+    subject     TEXT    NOT NULL,
+    linked_at   INTEGER NOT NULL,
+    UNIQUE (issuer, subject)
 );
 
 CREATE TABLE IF NOT EXISTS short_links(
-    id      INTEGER NOT NULL PRIMARY KEY,
-    user_id INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
-    url     TEXT    NOT NULL,
-    short   TEXT    NOT NULL UNIQUE
+    id          INTEGER NOT NULL PRIMARY KEY,
+    user_id     INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    url         TEXT    NOT NULL,
+    short       TEXT    NOT NULL UNIQUE,
+    created_at  INTEGER NOT NULL DEFAULT 0,
+    title       TEXT,
+    public      BOOLEAN NOT NULL DEFAULT FALSE
+);
+
+CREATE TABLE IF NOT EXISTS short_link_quotas(
+    user_id             INTEGER NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    max_links           INTEGER,
+    max_links_per_day   INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS user_totp(
+    user_id     INTEGER NOT NULL PRIMARY KEY REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    secret      TEXT    NOT NULL,
+    last_step   INTEGER NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS totp_recovery_codes(
+    id          INTEGER NOT NULL PRIMARY KEY,
+    user_id     INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    code_hash   TEXT    NOT NULL
+);
+
+CREATE TABLE IF NOT EXISTS webauthn_credentials(
+    id              INTEGER NOT NULL PRIMARY KEY,
+    user_id         INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    credential_id   TEXT    NOT NULL UNIQUE,
+    name            TEXT    NOT NULL,
+    passkey         TEXT    NOT NULL,
+    created_at      INTEGER NOT NULL,
+    last_used_at    INTEGER
+);
+
+CREATE TABLE IF NOT EXISTS user_sessions(
+    id          INTEGER NOT NULL PRIMARY KEY,
+    user_id     INTEGER NOT NULL REFERENCES users(id) ON DELETE CASCADE ON UPDATE CASCADE,
+    token       TEXT    NOT NULL UNIQUE,
+    created_at  INTEGER NOT NULL,
+    last_seen   INTEGER NOT NULL,
+    ip          TEXT,
+    user_agent  TEXT
+);
+
+CREATE TABLE IF NOT EXISTS session_states(
+    key         TEXT    NOT NULL PRIMARY KEY,
+    state       TEXT    NOT NULL,
+    expires_at  INTEGER NOT NULL
 );
 
+CREATE TABLE IF NOT EXISTS audit_log(
+    id          INTEGER NOT NULL PRIMARY KEY,
+    at          INTEGER NOT NULL,
+    event       TEXT    NOT NULL,
+    actor_id    INTEGER,
+    -- user ids can be reused once a user is deleted, so the name is kept as it was
+    actor_name  TEXT,
+    subject     TEXT    NOT NULL,
+    ip          TEXT,
+    user_agent  TEXT
+);
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_update BEFORE UPDATE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+
+CREATE TRIGGER IF NOT EXISTS audit_log_no_delete BEFORE DELETE ON audit_log
+BEGIN
+    SELECT RAISE(ABORT, 'the audit log is append-only');
+END;
+
 CREATE TABLE IF NOT EXISTS short_link_stats(
     id          INTEGER NOT NULL PRIMARY KEY,
     link_id     INTEGER NOT NULL REFERENCES short_links(id) ON DELETE CASCADE ON UPDATE CASCADE,
@@ -435,6 +2292,162 @@ CREATE TABLE IF NOT EXISTS short_link_stats(
     .unwrap();
 }
 
+/// Brings databases created by older versions of the website up to date with `create_tables`.
+#[cfg(not(feature = "prepare_db"))]
+async fn migrate(pool: &SqlitePool) {
+    add_column_if_missing(
+        pool,
+        "short_links",
+        "created_at",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    add_column_if_missing(pool, "short_links", "title", "TEXT").await;
+    add_column_if_missing(
+        pool,
+        "short_links",
+        "public",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    )
+    .await;
+    add_column_if_missing(
+        pool,
+        "users",
+        "public_clicks",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    )
+    .await;
+    add_column_if_missing(
+        pool,
+        "users",
+        "session_generation",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    add_column_if_missing(pool, "users", "disabled", "BOOLEAN NOT NULL DEFAULT FALSE").await;
+    // hashes from before pepper rotation were made with the pepper that's now key id 0
+    add_column_if_missing(pool, "users", "pepper_id", "INTEGER NOT NULL DEFAULT 0").await;
+    // users who registered before invites existed are roots of the invite tree
+    add_column_if_missing(
+        pool,
+        "users",
+        "invited_by",
+        "INTEGER REFERENCES users(id) ON DELETE SET NULL ON UPDATE CASCADE",
+    )
+    .await;
+    add_column_if_missing(
+        pool,
+        "registration_tickets",
+        "issued_by",
+        "INTEGER REFERENCES users(id) ON DELETE SET NULL ON UPDATE CASCADE",
+    )
+    .await;
+    add_column_if_missing(
+        pool,
+        "registration_tickets",
+        "issued_at",
+        "INTEGER NOT NULL DEFAULT 0",
+    )
+    .await;
+    // tickets issued before this column existed never expire
+    add_column_if_missing(pool, "registration_tickets", "expires_at", "INTEGER").await;
+    add_column_if_missing(
+        pool,
+        "registration_tickets",
+        "grant_admin",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    )
+    .await;
+    add_column_if_missing(
+        pool,
+        "registration_tickets",
+        "grant_short",
+        "BOOLEAN NOT NULL DEFAULT FALSE",
+    )
+    .await;
+    migrate_permissions_to_roles(pool).await;
+}
+
+/// Creates the built-in roles, and moves users from the old `user_permissions` table,
+/// which had a column per capability, to them.
+#[cfg(not(feature = "prepare_db"))]
+async fn migrate_permissions_to_roles(pool: &SqlitePool) {
+    let mut transaction = pool.begin().await.unwrap();
+
+    let roles = sqlx::query_scalar::<_, i64>("SELECT COUNT(*) FROM roles;")
+        .fetch_one(&mut *transaction)
+        .await
+        .unwrap();
+    if roles == 0 {
+        // every capability starts out with a role of its own
+        for capability in Capability::ALL {
+            let name = capability.as_str();
+            let rec = query!("INSERT INTO roles (name) VALUES (?) RETURNING id;", name)
+                .fetch_one(&mut *transaction)
+                .await
+                .unwrap();
+            query!(
+                "INSERT INTO role_capabilities (role_id, capability) VALUES (?, ?);",
+                rec.id,
+                name
+            )
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+        }
+    }
+
+    let legacy = sqlx::query_scalar::<_, i64>(
+        "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?);",
+    )
+    .bind("user_permissions")
+    .fetch_one(&mut *transaction)
+    .await
+    .unwrap();
+    if legacy == 1 {
+        for (column, role) in [("admin", ADMIN_ROLE), ("short", SHORT_ROLE)] {
+            sqlx::query(&format!(
+                "\
+INSERT OR IGNORE INTO user_roles (user_id, role_id)
+SELECT user_permissions.user_id, roles.id FROM user_permissions JOIN roles ON roles.name = ?
+WHERE user_permissions.{column};"
+            ))
+            .bind(role)
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+        }
+
+        sqlx::query("DROP TABLE user_permissions;")
+            .execute(&mut *transaction)
+            .await
+            .unwrap();
+    }
+
+    transaction.commit().await.unwrap();
+}
+
+#[cfg(not(feature = "prepare_db"))]
+async fn add_column_if_missing(pool: &SqlitePool, table: &str, column: &str, definition: &str) {
+    if sqlx::query_scalar::<_, i64>(
+        "SELECT EXISTS(SELECT 1 FROM pragma_table_info(?) WHERE name = ?);",
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_one(pool)
+    .await
+    .unwrap()
+        == 0
+    {
+        sqlx::query(&format!(
+            "ALTER TABLE {table} ADD COLUMN {column} {definition};"
+        ))
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+}
+
 #[cfg(feature = "prepare_db")]
 pub async fn prepare_db(filename: &str) {
     use sqlx::{Connection, SqliteConnection};
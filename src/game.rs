@@ -8,6 +8,8 @@ use askama::Template;
 use log::info;
 use serde::{Deserialize, Serialize};
 
+use crate::csrf::CsrfToken;
+
 const MESSAGE_LIMIT: usize = 10;
 const MESSAGE_MAX_LENGTH: usize = 1000;
 
@@ -22,14 +24,18 @@ pub struct GameMessage {
 #[template(path = "game.html")]
 struct GameTemplate {
     messages: Vec<GameMessage>,
+    csrf_token: String,
 }
 
 #[get("/game")]
-async fn game_get(data: Data<crate::AppData>) -> impl Responder {
+async fn game_get(data: Data<crate::AppData>, csrf: CsrfToken) -> impl Responder {
     let messages = data.state.messages.lock().await;
     let messages = messages.iter().cloned().collect();
 
-    GameTemplate { messages }
+    GameTemplate {
+        messages,
+        csrf_token: csrf.get().to_owned(),
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -9,6 +9,7 @@ use askama::Template;
 use std::sync::atomic::Ordering;
 
 use crate::auth::middleware::Login;
+use crate::csrf::CsrfToken;
 
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -16,6 +17,7 @@ struct IndexTemplate {
     visitors: i64,
     successful: Option<String>,
     logged_in: Option<String>,
+    csrf_token: String,
 }
 
 #[get("/")]
@@ -34,9 +36,17 @@ async fn index(
         None
     };
 
+    let logged_in = login.info().map(|x| x.name.clone());
+    // only the logout form needs a token, so visitors don't get a session just for looking
+    let csrf_token = match logged_in {
+        Some(_) => CsrfToken::for_session(&session).get().to_owned(),
+        None => String::new(),
+    };
+
     IndexTemplate {
         visitors,
         successful,
-        logged_in: login.info().map(|x| x.name.clone()),
+        logged_in,
+        csrf_token,
     }
 }
@@ -6,6 +6,17 @@ pub mod session_keys {
     pub const LOGGED_IN: &str = "logged_in";
     pub const SUCCESSFUL: &str = "successful";
     pub const NEW_SHORT: &str = "newshort";
+    pub const NEW_TICKET: &str = "newticket";
+    pub const NEW_RESET_TICKET: &str = "newresetticket";
+    pub const OIDC_LOGIN: &str = "oidc_login";
+    pub const OIDC_IDENTITY: &str = "oidc_identity";
+    pub const OIDC_REAUTH: &str = "oidc_reauth";
+    pub const OIDC_LINKED: &str = "oidc_linked";
+    pub const PENDING_LOGIN: &str = "pending_login";
+    pub const TOTP_SECRET: &str = "totp_secret";
+    pub const PASSKEY_REGISTRATION: &str = "passkey_registration";
+    pub const PASSKEY_AUTHENTICATION: &str = "passkey_authentication";
+    pub const CSRF_TOKEN: &str = "csrf_token";
 }
 
 const KEY_ENGINE: base64::engine::GeneralPurpose = base64::engine::general_purpose::STANDARD;
@@ -33,31 +44,45 @@ pub use inner::*;
 #[cfg(not(feature = "prepare_db"))]
 #[path = ""]
 mod inner {
+    pub mod account;
+    pub mod admin;
+    pub mod audit;
     pub mod auth;
+    pub mod csrf;
     pub mod discord_name;
     pub mod game;
     pub mod index;
     pub mod og;
+    pub mod profile;
+    pub mod session_store;
     pub mod short;
     pub mod ssl;
+    #[cfg(test)]
+    pub mod testing;
 
-    use crate::db::Db;
+    use crate::db::{Argon2Config, Db, InviteQuota, Peppers, ShortQuota};
     use actix_files::{Files, NamedFile};
     use actix_session::config::PersistentSession;
-    use actix_session::storage::RedisActorSessionStore;
     use actix_session::SessionMiddleware;
     use actix_web::cookie::time::Duration;
     use actix_web::web;
     use actix_web::{middleware, web::Data, App, HttpServer};
+    use auth::middleware::UserCache;
+    use auth::oidc::{OidcConfig, OidcProvider};
+    use auth::passkey::DecoyCredentials;
+    use auth::throttle::Throttle;
     use base64::Engine;
     use game::GameMessage;
-    use log::info;
+    use log::{info, warn};
     use serde::{Deserialize, Serialize};
+    use session_store::{SessionBackend, SessionStorage};
     use tokio::fs::File;
     use tokio::io::{AsyncBufReadExt, BufReader};
     use tokio::sync::Mutex;
+    use webauthn_rs::prelude::Url;
+    use webauthn_rs::{Webauthn, WebauthnBuilder};
 
-    use std::collections::VecDeque;
+    use std::collections::{HashMap, HashSet, VecDeque};
     use std::sync::atomic::AtomicI64;
     use std::sync::Arc;
 
@@ -68,6 +93,14 @@ mod inner {
         ssl: Option<SslConfig>,
         crypt: Keychain,
         session: SessionConfig,
+        #[serde(default)]
+        argon2: Argon2Config,
+        #[serde(default)]
+        short_quota: ShortQuota,
+        #[serde(default)]
+        invite_quota: InviteQuota,
+        webauthn: Option<WebauthnConfig>,
+        oidc: Option<OidcConfig>,
     }
 
     const fn bool_as_true() -> bool {
@@ -84,26 +117,54 @@ mod inner {
 
     #[derive(Serialize, Deserialize)]
     struct Keychain {
-        pepper: String,
+        /// The original pepper, which has key id 0.
+        pepper: Option<String>,
+        #[serde(default)]
+        peppers: Vec<PepperConfig>,
+        /// The key id of the pepper new hashes are made with. Defaults to the highest one.
+        current_pepper: Option<i64>,
         cookie: String,
     }
 
+    #[derive(Serialize, Deserialize)]
+    struct PepperConfig {
+        id: i64,
+        key: String,
+    }
+
     #[derive(Serialize, Deserialize)]
     struct SessionConfig {
-        redis_connection_string: String,
+        #[serde(default)]
+        backend: SessionBackend,
+        /// Only required by the redis backend.
+        redis_connection_string: Option<String>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    struct WebauthnConfig {
+        /// The domain passkeys are bound to, e.g. "boolco.dev".
+        rp_id: String,
+        /// The origin the site is served from, e.g. "https://boolco.dev".
+        rp_origin: String,
     }
 
     #[derive(Default, Debug)]
     struct AppState {
         visitors: AtomicI64,
         messages: Mutex<VecDeque<GameMessage>>,
+        login_throttle: Throttle,
     }
 
     #[derive(Debug)]
     pub struct AppData {
         state: AppState,
         dictionary: &'static [&'static str],
-        db: Db,
+        common_passwords: HashSet<String>,
+        db: Arc<Db>,
+        webauthn: Option<Webauthn>,
+        passkey_decoys: DecoyCredentials,
+        oidc: Option<OidcProvider>,
+        user_cache: UserCache,
     }
 
     pub async fn main() -> std::io::Result<()> {
@@ -117,21 +178,76 @@ mod inner {
         .expect("invalid config.toml format");
 
         let dictionary = init_dictionary("res/words_alpha.txt").await;
-
-        let pepper = crate::KEY_ENGINE
-            .decode(config.crypt.pepper)
-            .expect("couldn't decode pepper");
+        let common_passwords = init_common_passwords("res/common_passwords.txt").await;
+
+        let mut peppers = HashMap::new();
+        let legacy_pepper = config.crypt.pepper.map(|key| PepperConfig { id: 0, key });
+        for pepper in legacy_pepper.into_iter().chain(config.crypt.peppers) {
+            let key = crate::KEY_ENGINE
+                .decode(pepper.key)
+                .expect("couldn't decode pepper");
+            if peppers.insert(pepper.id, &*key.leak()).is_some() {
+                panic!("duplicate pepper key id {}", pepper.id);
+            }
+        }
+        let peppers = Peppers {
+            current: config
+                .crypt
+                .current_pepper
+                .or_else(|| peppers.keys().max().copied())
+                .expect("no pepper configured"),
+            keys: peppers,
+        };
         let cookie_key = crate::KEY_ENGINE
             .decode(config.crypt.cookie)
             .expect("couldn't decode cookie key");
+        // the cookie key is the one secret that's always configured, and decoys have to stay
+        // the same across restarts
+        let passkey_decoys = DecoyCredentials::new(cookie_key.clone().leak());
         let cookie_key = actix_web::cookie::Key::try_from(&*cookie_key)
             .expect("cookie key is too short (must be at least 64 bytes)");
-        let db = Db::new(crate::DATABASE_FILE, pepper.leak()).await;
+        let db = Db::new(
+            crate::DATABASE_FILE,
+            peppers,
+            config.argon2,
+            config.short_quota,
+            config.invite_quota,
+        )
+        .await;
+
+        let webauthn = config.webauthn.as_ref().map(|config| {
+            let origin = Url::parse(&config.rp_origin).expect("invalid webauthn rp_origin");
+            WebauthnBuilder::new(&config.rp_id, &origin)
+                .expect("invalid webauthn configuration")
+                .rp_name("boolco.dev")
+                .build()
+                .expect("invalid webauthn configuration")
+        });
+
+        let oidc = config.oidc.as_ref().and_then(OidcProvider::new);
 
+        let db = Arc::new(db);
         let data = Data::new(AppData {
             state: load_state(&db).await,
             dictionary: dictionary.leak(),
-            db,
+            common_passwords,
+            db: db.clone(),
+            webauthn,
+            passkey_decoys,
+            oidc,
+            user_cache: UserCache::default(),
+        });
+
+        let session_cleanup = (config.session.backend == SessionBackend::Sqlite).then(|| {
+            let db = db.clone();
+            actix_web::rt::spawn(async move {
+                let mut interval =
+                    actix_web::rt::time::interval(session_store::SQLITE_CLEANUP_INTERVAL);
+                loop {
+                    interval.tick().await;
+                    db.purge_session_states().await;
+                }
+            })
         });
 
         let server = {
@@ -141,18 +257,23 @@ mod inner {
                     .app_data(data.clone())
                     // enable logger
                     .wrap(middleware::Logger::default())
+                    // reject state-changing requests without the session's CSRF token
+                    .wrap(csrf::Csrf)
                     // logged-in user handling via middleware
                     .wrap(auth::middleware::Auth)
-                    // add redis-backed session storage
+                    // add session storage, backed by whatever the config says
                     .wrap(
                         SessionMiddleware::builder(
-                            RedisActorSessionStore::new(
-                                config.session.redis_connection_string.clone(),
+                            SessionStorage::new(
+                                config.session.backend,
+                                config.session.redis_connection_string.as_deref(),
+                                &db,
                             ),
                             cookie_key.clone(),
                         )
                         .session_lifecycle(
-                            PersistentSession::default().session_ttl(Duration::weeks(1)),
+                            PersistentSession::default()
+                                .session_ttl(Duration::seconds(crate::db::SESSION_LIFETIME)),
                         )
                         .cookie_same_site(actix_web::cookie::SameSite::Strict)
                         .build(),
@@ -169,11 +290,67 @@ mod inner {
                     .service(auth::register_get)
                     .service(auth::register_post)
                     .service(auth::register_path_post)
+                    .service(auth::reset_get)
+                    .service(auth::reset_post)
+                    .service(auth::second_factor_get)
+                    .service(auth::second_factor_post)
+                    .service(auth::passkey::login_start)
+                    .service(auth::passkey::login_finish)
+                    .service(auth::oidc::start)
+                    .service(auth::oidc::callback)
+                    .service(auth::oidc::finish)
+                    .service(auth::oidc::register_get)
+                    .service(auth::oidc::register_post)
                     .service(auth::logout)
+                    .service(account::account)
+                    .service(account::password_get)
+                    .service(account::password_post)
+                    .service(account::export)
+                    .service(account::delete_get)
+                    .service(account::delete_post)
+                    .service(account::oidc_get)
+                    .service(account::oidc_link)
+                    .service(account::totp_get)
+                    .service(account::totp_enable)
+                    .service(account::totp_disable)
+                    .service(account::totp_recovery)
+                    .service(account::sessions_get)
+                    .service(account::revoke_session)
+                    .service(account::revoke_other_sessions)
+                    .service(account::invites_get)
+                    .service(account::invites_post)
+                    .service(account::revoke_invite)
+                    .service(auth::passkey::passkeys_get)
+                    .service(auth::passkey::register_start)
+                    .service(auth::passkey::register_finish)
+                    .service(auth::passkey::delete)
+                    .service(admin::tickets_get)
+                    .service(admin::tickets_post)
+                    .service(admin::revoke_ticket)
+                    .service(admin::lockouts_get)
+                    .service(admin::clear_lockout)
+                    .service(admin::audit_get)
+                    .service(admin::users_get)
+                    .service(admin::set_roles)
+                    .service(admin::rename_user)
+                    .service(admin::set_disabled)
+                    .service(admin::reset_password)
+                    .service(admin::roles_get)
+                    .service(admin::create_role)
+                    .service(admin::set_role_capabilities)
+                    .service(admin::delete_role)
+                    .service(admin::invites_get)
+                    .service(admin::revoke_invite_branch)
                     .service(short::short_get)
                     .service(short::short_post)
+                    .service(short::export_csv)
+                    .service(short::export_json)
+                    .service(short::import)
+                    .service(short::set_visibility)
+                    .service(short::set_profile)
                     .service(short::short_link)
                     .service(short::delete_short)
+                    .service(profile::profile)
                     .service(Files::new("/static", "static").show_files_listing())
                     .route(
                         "/favicon.ico",
@@ -211,6 +388,9 @@ mod inner {
 
         let _ = server.run().await;
 
+        if let Some(session_cleanup) = session_cleanup {
+            session_cleanup.abort();
+        }
         let data = Arc::try_unwrap(data.into_inner()).unwrap();
         save_state(data.state, &data.db).await;
         Ok(())
@@ -235,6 +415,30 @@ mod inner {
         dictionary
     }
 
+    /// Loads the list of common or breached passwords that new passwords are checked against.
+    /// The list is optional, since the other password checks still work without it.
+    async fn init_common_passwords(filename: &str) -> HashSet<String> {
+        let mut passwords = HashSet::new();
+        let passwords_file = match File::open(filename).await {
+            Ok(file) => BufReader::new(file),
+            Err(err) => {
+                warn!("couldn't open {filename}, so common passwords are allowed: {err}");
+                return passwords;
+            }
+        };
+
+        let mut lines = passwords_file.lines();
+        while let Some(line) = lines.next_line().await.unwrap() {
+            // shorter passwords are rejected anyway
+            if line.len() < 8 {
+                continue;
+            }
+            passwords.insert(line.to_lowercase());
+        }
+
+        passwords
+    }
+
     async fn load_state(db: &Db) -> AppState {
         let visitors = db.get_visitors().await;
         let messages = db.get_messages().await;
@@ -242,11 +446,14 @@ mod inner {
         AppState {
             visitors: AtomicI64::new(visitors),
             messages: Mutex::new(messages.into()),
+            login_throttle: Throttle::default(),
         }
     }
 
     async fn save_state(state: AppState, db: &Db) {
-        let AppState { visitors, messages } = state;
+        let AppState {
+            visitors, messages, ..
+        } = state;
         db.set_visitors(visitors.into_inner()).await;
         db.set_messages(messages.into_inner().make_contiguous())
             .await;
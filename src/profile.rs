@@ -0,0 +1,61 @@
+use std::collections::HashMap;
+
+use actix_web::http::header::ContentType;
+use actix_web::http::StatusCode;
+use actix_web::web::{self, Data};
+use actix_web::{get, HttpResponseBuilder, Responder};
+use askama::Template;
+
+#[derive(Template)]
+#[template(path = "profile.html")]
+struct ProfileTemplate {
+    name: String,
+    links: Vec<PublicLink>,
+}
+
+struct PublicLink {
+    short: String,
+    title: String,
+    clicks: Option<i64>,
+}
+
+#[get("/u/{username}")]
+async fn profile(data: Data<crate::AppData>, username: web::Path<String>) -> impl Responder {
+    let Some(id) = data.db.get_user_id(&username).await else {
+        return HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish();
+    };
+
+    let clicks: HashMap<i64, i64> = if data.db.get_public_clicks(id).await {
+        data.db
+            .get_link_stats(id)
+            .await
+            .into_iter()
+            .map(|stat| (stat.link_id, stat.clicks))
+            .collect()
+    } else {
+        HashMap::new()
+    };
+
+    let links = data
+        .db
+        .get_links(id)
+        .await
+        .into_iter()
+        .filter(|link| link.public)
+        .map(|link| PublicLink {
+            clicks: clicks.get(&link.id).copied(),
+            title: link.title.unwrap_or_else(|| link.short.clone()),
+            short: link.short,
+        })
+        .collect();
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            ProfileTemplate {
+                name: username.into_inner(),
+                links,
+            }
+            .to_string(),
+        )
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use actix_session::storage::{
+    CookieSessionStore, LoadError, RedisActorSessionStore, SaveError, SessionKey, SessionStore,
+    UpdateError,
+};
+use actix_web::cookie::time::Duration;
+use rand::distributions::{Alphanumeric, DistString};
+use serde::{Deserialize, Serialize};
+
+use crate::db::{unix_now, Db};
+
+/// Where sessions are kept, as selected by `session.backend` in `config.toml`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SessionBackend {
+    #[default]
+    Redis,
+    /// The whole session is kept in the (encrypted) session cookie.
+    Cookie,
+    /// Sessions are kept in `data.sqlite`, next to everything else.
+    Sqlite,
+}
+
+/// How often expired sessions are removed from the SQLite store.
+pub const SQLITE_CLEANUP_INTERVAL: std::time::Duration = std::time::Duration::from_secs(60 * 60);
+
+const SESSION_KEY_LENGTH: usize = 64;
+
+/// A session store that can be any of the supported backends, so that the backend
+/// can be chosen at runtime.
+pub enum SessionStorage {
+    Redis(RedisActorSessionStore),
+    Cookie(CookieSessionStore),
+    Sqlite(SqliteSessionStore),
+}
+
+impl SessionStorage {
+    pub fn new(
+        backend: SessionBackend,
+        redis_connection_string: Option<&str>,
+        db: &Arc<Db>,
+    ) -> Self {
+        match backend {
+            SessionBackend::Redis => Self::Redis(RedisActorSessionStore::new(
+                redis_connection_string
+                    .expect("the redis session backend requires redis_connection_string"),
+            )),
+            SessionBackend::Cookie => Self::Cookie(CookieSessionStore::default()),
+            SessionBackend::Sqlite => Self::Sqlite(SqliteSessionStore { db: db.clone() }),
+        }
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SessionStorage {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        match self {
+            Self::Redis(store) => store.load(session_key).await,
+            Self::Cookie(store) => store.load(session_key).await,
+            Self::Sqlite(store) => store.load(session_key).await,
+        }
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        match self {
+            Self::Redis(store) => store.save(session_state, ttl).await,
+            Self::Cookie(store) => store.save(session_state, ttl).await,
+            Self::Sqlite(store) => store.save(session_state, ttl).await,
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        match self {
+            Self::Redis(store) => store.update(session_key, session_state, ttl).await,
+            Self::Cookie(store) => store.update(session_key, session_state, ttl).await,
+            Self::Sqlite(store) => store.update(session_key, session_state, ttl).await,
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        match self {
+            Self::Redis(store) => store.update_ttl(session_key, ttl).await,
+            Self::Cookie(store) => store.update_ttl(session_key, ttl).await,
+            Self::Sqlite(store) => store.update_ttl(session_key, ttl).await,
+        }
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        match self {
+            Self::Redis(store) => store.delete(session_key).await,
+            Self::Cookie(store) => store.delete(session_key).await,
+            Self::Sqlite(store) => store.delete(session_key).await,
+        }
+    }
+}
+
+/// Keeps sessions in the `session_states` table. Expired sessions are never loaded,
+/// and are removed periodically by [`crate::db::Db::purge_session_states`].
+pub struct SqliteSessionStore {
+    db: Arc<Db>,
+}
+
+impl SqliteSessionStore {
+    fn expires_at(ttl: &Duration) -> i64 {
+        unix_now() + ttl.whole_seconds()
+    }
+}
+
+#[async_trait::async_trait(?Send)]
+impl SessionStore for SqliteSessionStore {
+    async fn load(
+        &self,
+        session_key: &SessionKey,
+    ) -> Result<Option<HashMap<String, String>>, LoadError> {
+        self.db
+            .load_session_state(session_key.as_ref())
+            .await
+            .map(|state| serde_json::from_str(&state))
+            .transpose()
+            .map_err(|err| LoadError::Deserialization(err.into()))
+    }
+
+    async fn save(
+        &self,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, SaveError> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|err| SaveError::Serialization(err.into()))?;
+
+        // keys are long enough that a collision is practically impossible, but just in case
+        loop {
+            let key = Alphanumeric.sample_string(&mut rand::thread_rng(), SESSION_KEY_LENGTH);
+            if self
+                .db
+                .save_session_state(&key, &state, Self::expires_at(ttl))
+                .await
+            {
+                return SessionKey::try_from(key)
+                    .map_err(Into::into)
+                    .map_err(SaveError::Other);
+            }
+        }
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let state = serde_json::to_string(&session_state)
+            .map_err(|err| UpdateError::Serialization(err.into()))?;
+
+        if self
+            .db
+            .update_session_state(session_key.as_ref(), &state, Self::expires_at(ttl))
+            .await
+        {
+            Ok(session_key)
+        } else {
+            // the session expired in the meantime, so it gets a new key
+            self.save(session_state, ttl)
+                .await
+                .map_err(|err| match err {
+                    SaveError::Serialization(err) => UpdateError::Serialization(err),
+                    SaveError::Other(err) => UpdateError::Other(err),
+                })
+        }
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> anyhow::Result<()> {
+        self.db
+            .update_session_state_ttl(session_key.as_ref(), Self::expires_at(ttl))
+            .await;
+        Ok(())
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> anyhow::Result<()> {
+        self.db.delete_session_state(session_key.as_ref()).await;
+        Ok(())
+    }
+}
@@ -1,13 +1,21 @@
+use std::collections::HashMap;
+
+use actix_multipart::Multipart;
 use actix_session::Session;
-use actix_web::http::header::ContentType;
+use actix_web::http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType};
 use actix_web::http::StatusCode;
-use actix_web::web::{self, ReqData};
+use actix_web::web;
 use actix_web::{get, post, HttpRequest, HttpResponseBuilder, Responder};
 use askama::Template;
+use futures_util::TryStreamExt;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
-use crate::auth::middleware::Login;
+use crate::audit::{AuditEvent, RequestOrigin};
+use crate::auth::guard::{RequirePermission, Short};
+use crate::auth::middleware::UserInfo;
+use crate::csrf::CsrfToken;
+use crate::db::{ShortLinkError, ShortUsage};
 
 #[derive(Template)]
 #[template(path = "short.html")]
@@ -15,6 +23,26 @@ struct ShortTemplate {
     newshort: Option<String>,
     error: Option<String>,
     links: Vec<Link>,
+    usage: ShortUsage,
+    import_report: Option<ImportReport>,
+    name: String,
+    public_clicks: bool,
+    csrf_token: String,
+}
+
+impl ShortTemplate {
+    async fn load(data: &crate::AppData, info: &UserInfo, csrf: &CsrfToken) -> Self {
+        ShortTemplate {
+            newshort: None,
+            error: None,
+            links: data.db.get_links(info.id).await,
+            usage: data.db.get_short_usage(info.id).await,
+            import_report: None,
+            name: info.name.clone(),
+            public_clicks: data.db.get_public_clicks(info.id).await,
+            csrf_token: csrf.get().to_owned(),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -22,36 +50,38 @@ pub struct Link {
     pub short: String,
     pub url: String,
     pub id: i64,
+    pub title: Option<String>,
+    pub public: bool,
+    pub created_at: i64,
+}
+
+#[derive(Debug, Clone)]
+pub struct LinkStats {
+    pub link_id: i64,
+    pub clicks: i64,
+    pub unique_visitors: i64,
 }
 
 #[get("/short")]
 async fn short_get(
     data: web::Data<crate::AppData>,
-    login: ReqData<Login>,
+    info: RequirePermission<Short>,
+    csrf: CsrfToken,
     session: Session,
 ) -> impl Responder {
-    if let Some(info) = login.info() {
-        if info.perms.is_short() {
-            let newshort = session
-                .remove_as::<String>(crate::session_keys::NEW_SHORT)
-                .map(Result::unwrap);
-
-            let links = data.db.get_links(info.id).await;
-
-            return HttpResponseBuilder::new(StatusCode::OK)
-                .content_type(ContentType::html())
-                .body(
-                    ShortTemplate {
-                        newshort,
-                        error: None,
-                        links,
-                    }
-                    .to_string(),
-                );
-        }
-    }
+    let newshort = session
+        .remove_as::<String>(crate::session_keys::NEW_SHORT)
+        .map(Result::unwrap);
 
-    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            ShortTemplate {
+                newshort,
+                ..ShortTemplate::load(&data, &info, &csrf).await
+            }
+            .to_string(),
+        )
 }
 
 #[derive(Serialize, Deserialize)]
@@ -81,53 +111,305 @@ fn verify_shortstring(short: &str) -> bool {
         && short.len() <= 30
 }
 
+/// Describes why a link couldn't be created, if it wasn't the user's input that was at fault.
+fn quota_error(err: ShortLinkError) -> Option<String> {
+    match err {
+        ShortLinkError::TooManyLinks(max) => Some(format!(
+            "You have reached your limit of {max} short links. \
+            Please delete some of your existing links before creating new ones."
+        )),
+        ShortLinkError::TooManyToday(max) => Some(format!(
+            "You can only create {max} short links per day. Please try again later."
+        )),
+        ShortLinkError::NoSuchUser | ShortLinkError::Taken => None,
+    }
+}
+
+fn verify_link(link: &str) -> bool {
+    if let Ok(url) = Url::parse(link) {
+        let scheme = url.scheme();
+        scheme == "http" || scheme == "https"
+    } else {
+        false
+    }
+}
+
 #[post("/short")]
 async fn short_post(
     data: web::Data<crate::AppData>,
-    login: ReqData<Login>,
+    info: RequirePermission<Short>,
+    csrf: CsrfToken,
     form: web::Form<ShortForm>,
     session: Session,
 ) -> impl Responder {
-    if let Some(info) = login.info() {
-        if info.perms.is_short() {
-            if let Ok(url) = Url::parse(&form.link) {
-                let scheme = url.scheme();
-                if (scheme == "http" || scheme == "https")
-                    && form
-                        .shortstring
-                        .as_deref()
-                        .map(verify_shortstring)
-                        .unwrap_or(true)
-                {
-                    let short = data
-                        .db
-                        .create_short_link(info.id, &form.link, form.shortstring.as_deref())
-                        .await;
-                    if let Some(short) = short {
-                        session
-                            .insert(crate::session_keys::NEW_SHORT, short)
-                            .unwrap();
-
-                        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-                            .insert_header(("Location", "/short"))
-                            .finish();
+    let mut error = None;
+    if verify_link(&form.link)
+        && form
+            .shortstring
+            .as_deref()
+            .map(verify_shortstring)
+            .unwrap_or(true)
+    {
+        let short = data
+            .db
+            .create_short_link(info.id, &form.link, form.shortstring.as_deref())
+            .await;
+        match short {
+            Ok(short) => {
+                session
+                    .insert(crate::session_keys::NEW_SHORT, short)
+                    .unwrap();
+
+                return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+                    .insert_header(("Location", "/short"))
+                    .finish();
+            }
+            Err(err) => error = quota_error(err),
+        }
+    }
+
+    let (status, error) = if let Some(error) = error {
+        (StatusCode::TOO_MANY_REQUESTS, error)
+    } else {
+        (
+            StatusCode::BAD_REQUEST,
+            "Invalid request. Please make sure that the URL is valid, its scheme is http/https, \
+        and that your short value is unique."
+                .into(),
+        )
+    };
+    HttpResponseBuilder::new(status)
+        .content_type(ContentType::html())
+        .body(
+            ShortTemplate {
+                error: Some(error),
+                ..ShortTemplate::load(&data, &info, &csrf).await
+            }
+            .to_string(),
+        )
+}
+
+#[derive(Serialize)]
+struct ExportedLink {
+    short: String,
+    url: String,
+    clicks: i64,
+    unique_visitors: i64,
+}
+
+async fn export_links(data: &crate::AppData, user_id: i64) -> Vec<ExportedLink> {
+    let mut stats: HashMap<i64, LinkStats> = data
+        .db
+        .get_link_stats(user_id)
+        .await
+        .into_iter()
+        .map(|stat| (stat.link_id, stat))
+        .collect();
+
+    data.db
+        .get_links(user_id)
+        .await
+        .into_iter()
+        .map(|link| {
+            let (clicks, unique_visitors) = stats
+                .remove(&link.id)
+                .map(|stat| (stat.clicks, stat.unique_visitors))
+                .unwrap_or_default();
+
+            ExportedLink {
+                short: link.short,
+                url: link.url,
+                clicks,
+                unique_visitors,
+            }
+        })
+        .collect()
+}
+
+fn attachment(filename: &str) -> ContentDisposition {
+    ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(filename.into())],
+    }
+}
+
+#[get("/short/export.csv")]
+async fn export_csv(
+    data: web::Data<crate::AppData>,
+    info: RequirePermission<Short>,
+) -> impl Responder {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    for link in export_links(&data, info.id).await {
+        writer.serialize(link).unwrap();
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type("text/csv; charset=utf-8")
+        .insert_header(attachment("short_links.csv"))
+        .body(writer.into_inner().unwrap())
+}
+
+#[get("/short/export.json")]
+async fn export_json(
+    data: web::Data<crate::AppData>,
+    info: RequirePermission<Short>,
+) -> impl Responder {
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::json())
+        .insert_header(attachment("short_links.json"))
+        .body(serde_json::to_string(&export_links(&data, info.id).await).unwrap())
+}
+
+const IMPORT_MAX_SIZE: usize = 1024 * 1024; // bytes
+const IMPORT_MAX_ROWS: usize = 1000;
+
+#[derive(Debug, Clone, Default)]
+struct ImportReport {
+    created: usize,
+    errors: Vec<ImportError>,
+}
+
+#[derive(Debug, Clone)]
+struct ImportError {
+    line: u64,
+    message: String,
+}
+
+/// The same columns as the export, so an export can be imported back as-is.
+/// Any extra columns (such as the click counts) are ignored.
+#[derive(Deserialize)]
+struct ImportRow {
+    url: String,
+    #[serde(default)]
+    short: Option<String>,
+}
+
+/// Reads the CSRF token field, which has to come before the file, and then the file itself.
+/// Returns `None` for the file if it's missing or too large.
+async fn read_upload(mut payload: Multipart) -> (Option<String>, Option<Vec<u8>>) {
+    let mut token = None;
+    while let Ok(Some(mut field)) = payload.try_next().await {
+        match field.name() {
+            Some(crate::csrf::FIELD_NAME) => {
+                let mut value = vec![];
+                while let Ok(Some(chunk)) = field.try_next().await {
+                    // tokens are short, anything longer is wrong anyway
+                    if value.len() + chunk.len() > 256 {
+                        return (None, None);
                     }
+                    value.extend_from_slice(&chunk);
                 }
+                token = String::from_utf8(value).ok();
             }
-            return HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
-                .content_type(ContentType::html())
-                .body(
-                    ShortTemplate {
-                        newshort: None,
-                        error: Some("Invalid request. Please make sure that the URL is valid, its scheme is http/https, \
-                        and that your short value is unique.".into()),
-                        links: data.db.get_links(info.id).await
-                    }.to_string()
-                );
+            // the file isn't even read without a token
+            Some("file") if token.is_some() => return (token, read_file(field).await),
+            _ => {}
         }
     }
 
-    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+    (token, None)
+}
+
+async fn read_file(mut field: actix_multipart::Field) -> Option<Vec<u8>> {
+    let mut contents = vec![];
+    while let Some(chunk) = field.try_next().await.ok()? {
+        if contents.len() + chunk.len() > IMPORT_MAX_SIZE {
+            return None;
+        }
+        contents.extend_from_slice(&chunk);
+    }
+
+    Some(contents)
+}
+
+/// The CSRF middleware doesn't read multipart bodies, so the token is checked here.
+#[post("/short/import")]
+async fn import(
+    req: HttpRequest,
+    data: web::Data<crate::AppData>,
+    info: RequirePermission<Short>,
+    csrf: CsrfToken,
+    payload: Multipart,
+) -> impl Responder {
+    let (token, contents) = read_upload(payload).await;
+    if !token.is_some_and(|token| csrf.matches(&token)) {
+        return crate::csrf::rejected(&req).await;
+    }
+
+    let Some(contents) = contents else {
+        return HttpResponseBuilder::new(StatusCode::BAD_REQUEST)
+            .content_type(ContentType::html())
+            .body(
+                ShortTemplate {
+                    error: Some(format!(
+                        "Invalid upload. Please attach a CSV file no larger than {} KiB.",
+                        IMPORT_MAX_SIZE / 1024
+                    )),
+                    ..ShortTemplate::load(&data, &info, &csrf).await
+                }
+                .to_string(),
+            );
+    };
+
+    let mut report = ImportReport::default();
+    let mut reader = csv::Reader::from_reader(&*contents);
+    for (i, row) in reader.deserialize::<ImportRow>().enumerate() {
+        // the header is on line 1
+        let line = i as u64 + 2;
+        if i >= IMPORT_MAX_ROWS {
+            report.errors.push(ImportError {
+                line,
+                message: format!("only the first {IMPORT_MAX_ROWS} rows are imported at a time"),
+            });
+            break;
+        }
+
+        let message = match row {
+            Err(why) => format!("malformed row: {why}"),
+            Ok(row) if !verify_link(&row.url) => {
+                "the URL is invalid or its scheme isn't http/https".into()
+            }
+            Ok(ImportRow {
+                short: Some(short), ..
+            }) if !verify_shortstring(&short) => format!(
+                "\"{short}\" is not a valid short value (2-30 characters of a-z, A-Z, 0-9, _ and -)"
+            ),
+            Ok(row) => match data
+                .db
+                .create_short_link(info.id, &row.url, row.short.as_deref())
+                .await
+            {
+                Ok(_) => {
+                    report.created += 1;
+                    continue;
+                }
+                Err(err) => {
+                    if let Some(message) = quota_error(err) {
+                        report.errors.push(ImportError {
+                            line,
+                            message: format!(
+                                "{message} This and any later rows were not imported."
+                            ),
+                        });
+                        break;
+                    }
+                    "the short value is already taken".into()
+                }
+            },
+        };
+
+        report.errors.push(ImportError { line, message });
+    }
+
+    HttpResponseBuilder::new(StatusCode::OK)
+        .content_type(ContentType::html())
+        .body(
+            ShortTemplate {
+                import_report: Some(report),
+                ..ShortTemplate::load(&data, &info, &csrf).await
+            }
+            .to_string(),
+        )
 }
 
 #[get("/short/{link}")]
@@ -156,22 +438,84 @@ struct DeleteShortForm {
 
 #[post("/delete_short")]
 async fn delete_short(
+    req: HttpRequest,
     data: web::Data<crate::AppData>,
-    login: ReqData<Login>,
+    info: RequirePermission<Short>,
     form: web::Form<DeleteShortForm>,
 ) -> impl Responder {
-    if let Some(info) = login.info() {
-        if info.perms.is_short()
-            && data
-                .db
-                .delete_if_owns_short_link(info.id, &form.short)
-                .await
-        {
-            return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
-                .insert_header(("Location", "/short"))
-                .finish();
-        }
+    if data
+        .db
+        .delete_if_owns_short_link(info.id, &form.short)
+        .await
+    {
+        data.db
+            .audit(
+                AuditEvent::LinkDeleted,
+                Some(info.id),
+                &form.short,
+                &RequestOrigin::of(&req),
+            )
+            .await;
+
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/short"))
+            .finish();
+    }
+
+    HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
+}
+
+const TITLE_MAX_LENGTH: usize = 100; // bytes
+
+#[derive(Deserialize)]
+struct VisibilityForm {
+    short: String,
+    #[serde(deserialize_with = "empty_string_is_none")]
+    title: Option<String>,
+    public: Option<String>,
+}
+
+#[post("/short/visibility")]
+async fn set_visibility(
+    data: web::Data<crate::AppData>,
+    info: RequirePermission<Short>,
+    form: web::Form<VisibilityForm>,
+) -> impl Responder {
+    let title = form
+        .title
+        .as_deref()
+        .map(str::trim)
+        .filter(|x| !x.is_empty());
+    if title.map(|x| x.len() <= TITLE_MAX_LENGTH).unwrap_or(true)
+        && data
+            .db
+            .set_link_visibility(info.id, &form.short, title, form.public.is_some())
+            .await
+    {
+        return HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+            .insert_header(("Location", "/short"))
+            .finish();
     }
 
     HttpResponseBuilder::new(StatusCode::NOT_FOUND).finish()
 }
+
+#[derive(Deserialize)]
+struct ProfileForm {
+    public_clicks: Option<String>,
+}
+
+#[post("/short/profile")]
+async fn set_profile(
+    data: web::Data<crate::AppData>,
+    info: RequirePermission<Short>,
+    form: web::Form<ProfileForm>,
+) -> impl Responder {
+    data.db
+        .set_public_clicks(info.id, form.public_clicks.is_some())
+        .await;
+
+    HttpResponseBuilder::new(StatusCode::SEE_OTHER)
+        .insert_header(("Location", "/short"))
+        .finish()
+}
@@ -0,0 +1,113 @@
+//! Helpers for tests that drive the app through its handlers.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use actix_session::SessionMiddleware;
+use actix_web::cookie::{Cookie, Key};
+use actix_web::dev::ServiceResponse;
+use actix_web::test::TestRequest;
+use actix_web::web::Data;
+use rand::distributions::{Alphanumeric, DistString};
+use webauthn_rs::Webauthn;
+
+use super::auth::middleware::UserCache;
+use super::auth::oidc::OidcProvider;
+use super::auth::passkey::DecoyCredentials;
+use super::session_store::{SessionBackend, SessionStorage};
+use super::{AppData, AppState};
+use crate::audit::RequestOrigin;
+use crate::db::{unix_now, Argon2Config, Db, InviteQuota, Peppers, ShortQuota};
+
+const PEPPER: &[u8] = b"a pepper that is only used in tests";
+const SECRET: &[u8] = b"a secret that is only used in tests";
+
+/// App data backed by a fresh database in the temp directory.
+pub async fn app_data(webauthn: Option<Webauthn>, oidc: Option<OidcProvider>) -> Data<AppData> {
+    let filename = std::env::temp_dir().join(format!(
+        "boolco-test-{}.sqlite",
+        Alphanumeric.sample_string(&mut rand::thread_rng(), 16)
+    ));
+    let db = Db::new(
+        filename.to_str().unwrap(),
+        Peppers {
+            keys: HashMap::from([(0, PEPPER)]),
+            current: 0,
+        },
+        // hashing doesn't have to be slow in tests
+        Argon2Config {
+            memory_kib: argon2::Params::MIN_M_COST,
+            iterations: 1,
+            parallelism: 1,
+        },
+        ShortQuota::default(),
+        InviteQuota::default(),
+    )
+    .await;
+
+    Data::new(AppData {
+        state: AppState::default(),
+        dictionary: &[],
+        common_passwords: HashSet::new(),
+        db: Arc::new(db),
+        webauthn,
+        passkey_decoys: DecoyCredentials::new(SECRET),
+        oidc,
+        user_cache: UserCache::default(),
+    })
+}
+
+/// Registers a user through a ticket, like anyone else would be.
+pub async fn create_user(data: &AppData, name: &str, password: &str) -> i64 {
+    let ticket = data
+        .db
+        .generate_registration_ticket(name, None, unix_now() + 60, Default::default())
+        .await
+        .unwrap();
+    data.db
+        .register_user(&ticket, password, &RequestOrigin::default())
+        .await
+        .unwrap()
+}
+
+/// Session middleware that keeps sessions in the test database.
+pub fn sessions(data: &AppData) -> SessionMiddleware<SessionStorage> {
+    SessionMiddleware::new(
+        SessionStorage::new(SessionBackend::Sqlite, None, &data.db),
+        Key::from(&[7; 64][..]),
+    )
+}
+
+/// Keeps the cookies a client has been given, and sends them back.
+#[derive(Default)]
+pub struct Browser {
+    cookies: HashMap<String, Cookie<'static>>,
+}
+
+impl Browser {
+    pub fn request(&self, mut req: TestRequest) -> TestRequest {
+        for cookie in self.cookies.values() {
+            req = req.cookie(cookie.clone());
+        }
+        req
+    }
+
+    pub fn update<B>(&mut self, res: &ServiceResponse<B>) {
+        for cookie in res.response().cookies() {
+            let cookie = cookie.into_owned();
+            if cookie.value().is_empty() {
+                self.cookies.remove(cookie.name());
+            } else {
+                self.cookies.insert(cookie.name().to_owned(), cookie);
+            }
+        }
+    }
+}
+
+/// The CSRF token in a page that has a form.
+pub fn csrf_token(page: &[u8]) -> String {
+    let page = std::str::from_utf8(page).unwrap();
+    let start = page.find(r#"name="csrf_token" value=""#).unwrap() + 25;
+    let end = start + page[start..].find('"').unwrap();
+    page[start..end].to_owned()
+}
@@ -0,0 +1,103 @@
+// WebAuthn sends binary data as base64url strings in JSON, but the browser API wants ArrayBuffers.
+function toBuffer(base64url) {
+    const base64 = base64url.replace(/-/g, "+").replace(/_/g, "/");
+    const padded = base64 + "=".repeat((4 - base64.length % 4) % 4);
+    return Uint8Array.from(atob(padded), (c) => c.charCodeAt(0)).buffer;
+}
+
+function toBase64url(buffer) {
+    const base64 = btoa(String.fromCharCode(...new Uint8Array(buffer)));
+    return base64.replace(/\+/g, "-").replace(/\//g, "_").replace(/=+$/, "");
+}
+
+// Every POST has to carry the CSRF token, which pages using this script put in a meta tag.
+function csrfToken() {
+    return document.querySelector("meta[name=csrf-token]").content;
+}
+
+async function postJson(url, body) {
+    const response = await fetch(url, {
+        method: "POST",
+        headers: { "Content-Type": "application/json", "X-CSRF-Token": csrfToken() },
+        body: JSON.stringify(body),
+    });
+    if (!response.ok) {
+        throw new Error("the server rejected the request");
+    }
+    return response.json();
+}
+
+async function registerPasskey(name) {
+    const options = await postJson("/account/passkeys/register/start", {});
+    options.publicKey.challenge = toBuffer(options.publicKey.challenge);
+    options.publicKey.user.id = toBuffer(options.publicKey.user.id);
+    for (const credential of options.publicKey.excludeCredentials || []) {
+        credential.id = toBuffer(credential.id);
+    }
+
+    const credential = await navigator.credentials.create(options);
+    return postJson("/account/passkeys/register/finish", {
+        name: name,
+        credential: {
+            id: credential.id,
+            rawId: toBase64url(credential.rawId),
+            type: credential.type,
+            response: {
+                attestationObject: toBase64url(credential.response.attestationObject),
+                clientDataJSON: toBase64url(credential.response.clientDataJSON),
+            },
+            extensions: credential.getClientExtensionResults(),
+        },
+    });
+}
+
+async function loginWithPasskey(username, next) {
+    const options = await postJson("/login/passkey/start", { username: username });
+    options.publicKey.challenge = toBuffer(options.publicKey.challenge);
+    for (const credential of options.publicKey.allowCredentials || []) {
+        credential.id = toBuffer(credential.id);
+    }
+
+    const credential = await navigator.credentials.get(options);
+    const userHandle = credential.response.userHandle;
+    return postJson("/login/passkey/finish?" + new URLSearchParams({ next: next }), {
+        id: credential.id,
+        rawId: toBase64url(credential.rawId),
+        type: credential.type,
+        response: {
+            authenticatorData: toBase64url(credential.response.authenticatorData),
+            clientDataJSON: toBase64url(credential.response.clientDataJSON),
+            signature: toBase64url(credential.response.signature),
+            userHandle: userHandle ? toBase64url(userHandle) : null,
+        },
+        extensions: credential.getClientExtensionResults(),
+    });
+}
+
+window.onload = function () {
+    const status = document.querySelector("#passkey_status");
+    const fail = (error) => {
+        status.textContent = "Passkey error: " + error.toString();
+    };
+
+    const registerForm = document.querySelector("#passkey_register");
+    if (registerForm) {
+        registerForm.addEventListener("submit", (event) => {
+            event.preventDefault();
+            registerPasskey(document.querySelector("#passkey_name").value)
+                .then(() => window.location.reload())
+                .catch(fail);
+        });
+    }
+
+    const loginForm = document.querySelector("#passkey_login");
+    if (loginForm) {
+        loginForm.addEventListener("submit", (event) => {
+            event.preventDefault();
+            const username = document.querySelector("#passkey_username").value;
+            loginWithPasskey(username, loginForm.elements.next.value)
+                .then((data) => window.location.assign(data.redirect))
+                .catch(fail);
+        });
+    }
+}